@@ -0,0 +1,113 @@
+//! Throughput of `encrypt` and `decrypt`, including replay protection bookkeeping and
+//! rejection of forged datagrams.
+//! Run with `cargo bench`.
+
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use moshudp::protocol::{decrypt, encrypt, Cipher, CipherKind, Message, ReplayGuard};
+
+/// Number of nonces remembered by the replay guard in the "full" variant
+const FULL_REPLAY_GUARD: usize = 1_000_000;
+
+fn messages() -> Vec<(&'static str, Message)> {
+    vec![
+        ("ping", Message::Ping { seq: 1 }),
+        (
+            "server_started",
+            Message::ServerStarted {
+                key: "AAAAAAAAAAAAAAAAAAAAAA".to_owned(),
+            },
+        ),
+        (
+            "failed_512",
+            Message::Failed {
+                msg: "x".repeat(512),
+            },
+        ),
+    ]
+}
+
+fn ciphers() -> Vec<(&'static str, CipherKind)> {
+    vec![
+        ("xchacha20poly1305", CipherKind::XChaCha20Poly1305),
+        ("aes256gcm", CipherKind::Aes256Gcm),
+    ]
+}
+
+fn bench_encrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt");
+    for (cipher_name, kind) in ciphers() {
+        let crypto = Cipher::new(kind, &[7u8; 32]);
+        for (msg_name, msg) in messages() {
+            group.bench_with_input(BenchmarkId::new(cipher_name, msg_name), &msg, |b, msg| {
+                b.iter(|| encrypt(black_box(msg), &crypto, false, None).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_decrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decrypt");
+    for (cipher_name, kind) in ciphers() {
+        let crypto = Cipher::new(kind, &[7u8; 32]);
+        let mut replay_guard = ReplayGuard::new(Duration::from_secs(3600));
+        for (msg_name, msg) in messages() {
+            group.bench_with_input(BenchmarkId::new(cipher_name, msg_name), &msg, |b, msg| {
+                b.iter_batched(
+                    || encrypt(msg, &crypto, false, None).unwrap(),
+                    |pkt| decrypt(black_box(&pkt), &crypto, &mut replay_guard).unwrap(),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Cost of rejecting a forged datagram, which fails at the AEAD tag before any decryption
+/// or replay bookkeeping
+fn bench_decrypt_forged(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decrypt_forged");
+    for (cipher_name, kind) in ciphers() {
+        let crypto = Cipher::new(kind, &[7u8; 32]);
+        let mut replay_guard = ReplayGuard::new(Duration::from_secs(3600));
+        let mut pkt = encrypt(&Message::Ping { seq: 1 }, &crypto, false, None).unwrap();
+        *pkt.last_mut().unwrap() ^= 1;
+        group.bench_function(cipher_name, |b| {
+            b.iter(|| decrypt(black_box(&pkt), &crypto, &mut replay_guard).unwrap_err())
+        });
+    }
+    group.finish();
+}
+
+/// Cost of decryption when the replay guard already remembers many nonces
+fn bench_decrypt_full_replay_guard(c: &mut Criterion) {
+    let crypto = Cipher::new(CipherKind::XChaCha20Poly1305, &[7u8; 32]);
+    let msg = Message::Ping { seq: 1 };
+    let mut replay_guard = ReplayGuard::new(Duration::from_secs(3600));
+    for _ in 0..FULL_REPLAY_GUARD {
+        let pkt = encrypt(&msg, &crypto, false, None).unwrap();
+        decrypt(&pkt, &crypto, &mut replay_guard).unwrap();
+    }
+
+    let mut group = c.benchmark_group("decrypt_replay_guard");
+    group.bench_function(format!("{}_nonces", FULL_REPLAY_GUARD), |b| {
+        b.iter_batched(
+            || encrypt(&msg, &crypto, false, None).unwrap(),
+            |pkt| decrypt(black_box(&pkt), &crypto, &mut replay_guard).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_encrypt,
+    bench_decrypt,
+    bench_decrypt_forged,
+    bench_decrypt_full_replay_guard
+);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+//! Arbitrary bytes from the network must only ever produce `Ok` or `Err`.
+//! Run with `cargo fuzz run decrypt`.
+
+#![no_main]
+
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+use moshudp::protocol::{decrypt, Cipher, CipherKind, ReplayGuard};
+
+fuzz_target!(|data: &[u8]| {
+    for kind in [CipherKind::XChaCha20Poly1305, CipherKind::Aes256Gcm] {
+        let crypto = Cipher::new(kind, &[7u8; 32]);
+        let mut replay_guard = ReplayGuard::new(Duration::from_secs(3600));
+        let _ = decrypt(data, &crypto, &mut replay_guard);
+    }
+});
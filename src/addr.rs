@@ -0,0 +1,98 @@
+//! Socket address lookup that also accepts interface names as IPv6 zone, like `[fe80::1%eth0]:22`.
+//! Plain `ToSocketAddrs` only understands numeric zones.
+
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+};
+
+/// Resolve `addr` like `ToSocketAddrs`, keeping the scope id of link-local addresses
+pub fn resolve(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    match parse_named_zone(addr)? {
+        Some(sa) => Ok(vec![sa]),
+        None => Ok(addr.to_socket_addrs()?.collect()),
+    }
+}
+
+/// `[ip%zone]:port` with non-numeric zone, `None` for other forms
+fn parse_named_zone(addr: &str) -> io::Result<Option<SocketAddr>> {
+    let (host, port) = match addr.strip_prefix('[').and_then(|x| x.split_once("]:")) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let (ip, zone) = match host.split_once('%') {
+        Some((_, zone)) if zone.parse::<u32>().is_ok() => return Ok(None),
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let invalid = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} in {}", what, addr),
+        )
+    };
+    let ip: Ipv6Addr = ip.parse().map_err(|_| invalid("IPv6 address"))?;
+    let port: u16 = port.parse().map_err(|_| invalid("port"))?;
+    let scope_id = interface_index(zone)?;
+    let sa = SocketAddrV6::new(ip, port, 0, scope_id);
+    Ok(Some(SocketAddr::V6(sa)))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> io::Result<u32> {
+    nix::net::if_::if_nametoindex(name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No network interface {}", name),
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Use numeric interface index as IPv6 zone",
+    ))
+}
+
+/// Link-local addresses are only meaningful together with a scope id
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric_zone() {
+        let addrs = resolve("[fe80::1%3]:22").unwrap();
+        assert_eq!(
+            addrs,
+            [SocketAddr::V6(SocketAddrV6::new(
+                "fe80::1".parse().unwrap(),
+                22,
+                0,
+                3
+            ))]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn named_zone() {
+        let addrs = resolve("[fe80::1%lo]:22").unwrap();
+        let lo = nix::net::if_::if_nametoindex("lo").unwrap();
+        assert_eq!(
+            addrs,
+            [SocketAddr::V6(SocketAddrV6::new(
+                "fe80::1".parse().unwrap(),
+                22,
+                0,
+                lo
+            ))]
+        );
+        assert!(resolve("[fe80::1%no-such-interface]:22").is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! Receiving several datagrams per wakeup, with `recvmmsg(2)` where available.
+
+use std::net::{SocketAddr, UdpSocket};
+
+/// Maximum number of datagrams received at once
+pub const BATCH_SIZE: usize = 32;
+
+/// Buffers for a batch of datagrams, each of them `slot_size` bytes long
+pub struct RecvBatch {
+    buf: Vec<u8>,
+    slot_size: usize,
+    /// Slot index, length and source address of each received datagram
+    packets: Vec<(usize, usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    pub fn new(slot_size: usize) -> RecvBatch {
+        RecvBatch {
+            buf: vec![0u8; slot_size * BATCH_SIZE],
+            slot_size,
+            packets: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Number of datagrams received by the last `recv_from`
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Datagram number `i` of the last `recv_from`
+    pub fn get(&self, i: usize) -> (&[u8], SocketAddr) {
+        let (slot, len, addr) = self.packets[i];
+        let start = slot * self.slot_size;
+        (&self.buf[start..start + len], addr)
+    }
+
+    /// Receives up to [`BATCH_SIZE`] datagrams that are already queued on a readable socket
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn recv_from(&mut self, socket: &UdpSocket) -> std::io::Result<()> {
+        use nix::sys::{
+            socket::{recvmmsg, MsgFlags, RecvMmsgData, SockAddr},
+            uio::IoVec,
+        };
+        use std::os::unix::io::AsRawFd;
+
+        self.packets.clear();
+        let mut data: Vec<RecvMmsgData<[IoVec<&mut [u8]>; 1]>> = self
+            .buf
+            .chunks_mut(self.slot_size)
+            .map(|slot| RecvMmsgData {
+                iov: [IoVec::from_mut_slice(slot)],
+                cmsg_buffer: None,
+            })
+            .collect();
+        let msgs = recvmmsg(socket.as_raw_fd(), &mut data, MsgFlags::MSG_DONTWAIT, None)?;
+        for (slot, msg) in msgs.iter().enumerate() {
+            if let Some(SockAddr::Inet(addr)) = msg.address {
+                self.packets.push((slot, msg.bytes, addr.to_std()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives one datagram, there is no `recvmmsg` on this platform
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn recv_from(&mut self, socket: &UdpSocket) -> std::io::Result<()> {
+        self.packets.clear();
+        let (len, addr) = socket.recv_from(&mut self.buf[..self.slot_size])?;
+        self.packets.push((0, len, addr));
+        Ok(())
+    }
+}
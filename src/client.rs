@@ -1,180 +1,606 @@
 use std::{
-    ffi::OsStr,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
-    os::unix::prelude::AsRawFd,
+    ffi::OsString,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
-use chacha20poly1305::XChaCha20Poly1305;
-use fxhash::FxHashSet;
-use nix::poll::{poll, PollFd, PollFlags};
+use log::{debug, error, info, warn};
 
-use crate::protocol::{Message, Nonce};
-use std::os::unix::ffi::OsStrExt;
+use crate::batch::RecvBatch;
+use crate::mtu::MtuCheck;
+use crate::poller::{PlatformPoller, Poller};
+use crate::protocol::{Cipher, CookieToken, DecryptError, Message, ReplayGuard};
+use crate::send_error::is_transient;
+use crate::sockbuf::SocketBuffers;
+use crate::socks5::{self, Socks5Udp};
+use crate::throttle::{Throttle, ThrottleOptions};
+use crate::trace::{self, Payload};
+
+/// Silence from mosh-client after which datagrams from other local ports are accepted
+const REPLY_ADDRESS_RELEARN: Duration = Duration::from_secs(5);
+/// Silence from server after which its hostname is resolved again in `--roam` mode, and
+/// handshake is redone in `--reconnect` mode. mosh-server sends heartbeats every few seconds.
+const ROAM_TIMEOUT: Duration = Duration::from_secs(15);
+/// Resend interval of requests stops doubling here, unless `retry_interval` is longer
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+/// `seq` of keepalive pings, their replies are not reported
+const KEEPALIVE_SEQ: u64 = u64::MAX;
+/// How often the waiter thread checks whether mosh-client has exited
+const MOSH_CLIENT_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+/// mosh-client is killed if it does not exit this long after being asked to
+const MOSH_CLIENT_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Exit status of `connect`. Errors returned from `main` also exit with [`ExitCode::Error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    /// mosh-client exited successfully, or ping/status query succeeded
+    Success = 0,
+    /// Invalid options or other setup error
+    Error = 1,
+    /// No usable reply from server, or too many pings lost. Server does not reply to
+    /// requests with wrong key, so this is also the usual result of a key mismatch.
+    Unreachable = 2,
+    /// Cannot send to server, or cannot start or wait for mosh-client
+    LocalError = 3,
+    /// mosh-client exited with unsuccessful status that has no exit code of its own.
+    /// Otherwise `connect` exits with the same code as mosh-client, or 128 plus the number
+    /// of the signal that killed it.
+    MoshClientFailed = 4,
+    /// Server replied with `Failed`, e.g. mosh-server could not be started
+    ServerFailed = 5,
+    /// Server replied only with moshudp datagrams that could not be decrypted:
+    /// wrong key, cipher or moshudp version
+    AuthFailed = 6,
+}
+
+impl ExitCode {
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+/// Resolves server address again for `--roam` mode
+pub type Resolver = Box<dyn Fn() -> anyhow::Result<Vec<SocketAddr>>>;
 
 pub struct Client {
+    /// Sends to `destination_address`, or to SOCKS5 relay if `socks5` is set
     client_socket: UdpSocket,
-    crypto: XChaCha20Poly1305,
+    /// `client_socket` is connected to `destination_address`, so kernel drops datagrams from other addresses
+    connected: bool,
+    socks5: Option<Socks5Udp>,
+    crypto: Cipher,
+    /// Compress outgoing messages, if built with `compression` feature
+    compress: bool,
+    /// Pad outgoing messages to a multiple of this size
+    pad_to: Option<usize>,
     mosh: Option<MoshClientState>,
-    past_nonces: FxHashSet<Nonce>,
+    replay_guard: ReplayGuard,
     destination_address: SocketAddr,
-    resend_counter: usize,
+    /// Addresses to try if `destination_address` does not reply
+    remaining_addresses: Vec<SocketAddr>,
+    /// When to resend the request if there is no reply
+    next_resend: Instant,
+    /// Delay before the resend after the next one, doubled after each resend
+    resend_interval: Duration,
+    /// When to give up on `destination_address`
+    address_deadline: Instant,
     sessid: u64,
+    /// Token from server's `Cookie` reply, included in `StartServer`
+    cookie: Option<CookieToken>,
     ping_mode: bool,
+    /// Query server status instead of starting mosh
+    status_mode: bool,
+    /// Datagrams from server that failed to decrypt, to report likely key mismatch
+    auth_failures: usize,
+    buffer_size: usize,
+    /// Inserted into mosh-client command line before host and port
+    mosh_client_args: Vec<String>,
+    retry_interval: Duration,
+    connect_timeout: Duration,
+    probes: Option<PingProbes>,
+    /// Send time of each `Ping`, indexed by `seq - ping_seq_base`. Taken when reply arrives.
+    ping_send_times: Vec<Option<Instant>>,
+    /// `seq` of `ping_send_times[0]`. Advanced in `--watch` mode as pings are answered or lost.
+    ping_seq_base: u64,
+    roam: Option<Resolver>,
+    /// Last datagram from `destination_address`
+    last_server_activity: Instant,
+    /// Ping server if nothing was sent to it for this long while mosh is running
+    keepalive: Option<Duration>,
+    /// Last datagram to `destination_address`
+    last_sent: Instant,
+    /// Bandwidth limit of mosh traffic towards server
+    throttle: Option<Throttle>,
+    /// Where to bind `client_socket`
+    local: LocalAddr,
+    mtu_check: Option<MtuCheck>,
+    /// Local address for `--listen` relay mode
+    listen: Option<SocketAddr>,
+    /// Print mosh key and local port instead of starting mosh-client
+    emit_key: bool,
+    /// Redo the handshake when server goes silent during a session
+    reconnect: bool,
+    /// Handshake is being redone, `StartServer` is resent until server replies
+    reconnecting: bool,
+    /// mosh key from the first `ServerStarted`, to tell a resumed session from a new one
+    session_key: Option<String>,
+    /// `client_socket` was replaced or mosh-client started since sockets were last given to
+    /// the poller
+    sockets_changed: bool,
+}
+
+/// Settings of [`Client`] that come from command line
+pub struct ClientOptions {
+    pub ping_mode: bool,
+    /// Send `Status` request and print the reply instead of starting mosh
+    pub status_mode: bool,
+    pub buffer_size: usize,
+    pub replay_window: Duration,
+    pub mosh_client_args: Vec<String>,
+    /// Delay before the first resend of request while there is no reply. Doubles with each
+    /// resend up to 3 seconds.
+    pub retry_interval: Duration,
+    /// Give up if there is no usable reply from server after this time
+    pub connect_timeout: Duration,
+    /// Number of pings to send in ping mode. If `None`, single ping is resent until reply.
+    pub ping_count: Option<usize>,
+    pub ping_interval: Duration,
+    /// Exit with error if more than this percentage of pings are lost
+    pub max_loss: f64,
+    /// In ping mode, ping every `ping_interval` until killed, printing a timestamped line
+    /// per reply or lost ping
+    pub watch: bool,
+    /// Compress outgoing messages, if built with `compression` feature
+    pub compress: bool,
+    /// Pad outgoing messages to a multiple of this size
+    pub pad_to: Option<usize>,
+    /// Follow server to a new address: re-resolve it when server goes silent and
+    /// accept authenticated replies from other addresses
+    pub roam: Option<Resolver>,
+    /// Keep NAT mapping alive by pinging server if nothing was sent to it for this long
+    pub keepalive: Option<Duration>,
+    /// Send datagrams through this SOCKS5 proxy using UDP ASSOCIATE
+    pub socks5: Option<SocketAddr>,
+    /// Bandwidth limit of mosh traffic towards server
+    pub throttle: Option<ThrottleOptions>,
+    /// Local address to send from instead of unspecified one. Server addresses of other family are skipped.
+    pub bind: Option<IpAddr>,
+    /// Local port to send from instead of ephemeral one
+    pub local_port: Option<u16>,
+    /// Warn about datagrams to server that exceed this size with IP and UDP headers
+    pub mtu: Option<usize>,
+    /// DSCP value to mark datagrams to server with
+    pub dscp: Option<u8>,
+    /// Kernel buffer sizes of the socket to server and the mosh-client-facing one
+    pub socket_buffers: SocketBuffers,
+    /// Network interface to pin the socket to server to
+    pub bind_device: Option<String>,
+    /// Exchange forwarded traffic with whatever sends to this local address instead of
+    /// starting mosh-client
+    pub listen: Option<SocketAddr>,
+    /// Print mosh key and port of the local relay socket to stdout instead of starting
+    /// mosh-client, for mosh-client started by the user. Relay socket is at `listen` if set.
+    pub emit_key: bool,
+    /// When server goes silent during a session, redo the handshake with the same session id
+    /// from a fresh socket to resume it
+    pub reconnect: bool,
+}
+
+/// Progress of ping mode with specified count, like ping(8)
+struct PingProbes {
+    /// `None` in `--watch` mode, which never finishes
+    count: Option<usize>,
+    interval: Duration,
+    max_loss: f64,
+    sent: usize,
+    received: usize,
+    next_send: Instant,
+    /// When to stop waiting for replies after the last ping is sent
+    deadline: Option<Instant>,
+    rtts: Vec<Duration>,
 }
 
 struct MoshClientState {
     socket: UdpSocket,
+    /// Address of mosh-client, learned from its datagrams
     reply_address: Option<SocketAddr>,
-    //child: std::process::Child,
+    /// Last datagram from `reply_address`
+    last_reply_activity: Instant,
+    /// `socket` is bound to loopback, so datagrams from elsewhere are not from mosh-client
+    loopback_only: bool,
+    /// mosh-client we started, stopped on drop. `None` in `--listen` and `--emit-key` modes.
+    child: Option<MoshClientProcess>,
+    /// `socket` is connected to `reply_address`, so the kernel drops datagrams from elsewhere.
+    /// Undone after [`REPLY_ADDRESS_RELEARN`] of silence, as mosh-client changes its port
+    /// when it stops hearing from the server.
+    connected: bool,
+}
+
+impl MoshClientState {
+    /// Accept datagrams only from `addr` from now on. Without support for disconnecting
+    /// afterwards, `reply_address` is checked for each datagram instead.
+    fn connect_to(&mut self, addr: SocketAddr) {
+        if !cfg!(unix) {
+            return;
+        }
+        match self.socket.connect(addr) {
+            Ok(()) => self.connected = true,
+            Err(e) => debug!(
+                "Cannot connect mosh-client-facing socket to {}: {}",
+                addr, e
+            ),
+        }
+    }
+
+    /// When silence of `reply_address` allows another local port, if it is connected to
+    fn relearn_deadline(&self) -> Option<Instant> {
+        let loopback = matches!(self.reply_address, Some(x) if x.ip().is_loopback());
+        (self.connected && loopback).then(|| self.last_reply_activity + REPLY_ADDRESS_RELEARN)
+    }
+
+    /// Disconnects `socket` after [`MoshClientState::relearn_deadline`]
+    fn relearn_tick(&mut self) {
+        match self.relearn_deadline() {
+            Some(t) if Instant::now() >= t => match disconnect(&self.socket) {
+                Ok(()) => self.connected = false,
+                Err(e) => {
+                    debug!("Cannot disconnect mosh-client-facing socket: {}", e);
+                    // Try again later instead of spinning
+                    self.last_reply_activity = Instant::now();
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn send(&self, pkt: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        if self.connected {
+            self.socket.send(pkt)
+        } else {
+            self.socket.send_to(pkt, addr)
+        }
+    }
+}
+
+/// Undo `connect` of UDP socket, keeping its local address
+#[cfg(unix)]
+fn disconnect(socket: &UdpSocket) -> std::io::Result<()> {
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: all-zero sockaddr is valid
+    let mut addr: libc::sockaddr = unsafe { std::mem::zeroed() };
+    addr.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+    // Safety: the address is a live sockaddr and its size is passed along
+    let ret = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &addr,
+            std::mem::size_of::<libc::sockaddr>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn disconnect(_socket: &UdpSocket) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// mosh-client process, shared with the thread waiting for it. Taken out by
+/// [`MoshClientProcess::stop`], which tells the thread to quit without exiting the process.
+struct MoshClientProcess(Arc<Mutex<Option<Child>>>);
+
+impl MoshClientProcess {
+    fn stop(&self) {
+        let child = match self.0.lock() {
+            Ok(mut x) => x.take(),
+            Err(_) => return,
+        };
+        if let Some(mut child) = child {
+            debug!("Stopping mosh-client");
+            terminate(&mut child);
+        }
+    }
+}
+
+/// Asks `child` to exit, so that mosh-client restores the terminal, and kills it if it does
+/// not exit within [`MOSH_CLIENT_STOP_TIMEOUT`]
+fn terminate(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+
+        if kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).is_ok() {
+            let deadline = Instant::now() + MOSH_CLIENT_STOP_TIMEOUT;
+            while Instant::now() < deadline {
+                if let Ok(Some(_)) = child.try_wait() {
+                    return;
+                }
+                std::thread::sleep(MOSH_CLIENT_WAIT_INTERVAL);
+            }
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
 }
 
 impl Client {
+    /// `dest_addrs` are tried one by one until some of them replies.
+    /// IPv6 addresses are tried first.
     pub fn new(
-        dest_sa: SocketAddr,
-        crypto: XChaCha20Poly1305,
-        ping_mode: bool,
+        mut dest_addrs: Vec<SocketAddr>,
+        crypto: Cipher,
+        opts: ClientOptions,
     ) -> anyhow::Result<Client> {
-        let bind_sa = match dest_sa {
-            SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
-            SocketAddr::V6(_) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+        let ClientOptions {
+            ping_mode,
+            status_mode,
+            buffer_size,
+            replay_window,
+            mosh_client_args,
+            retry_interval,
+            connect_timeout,
+            ping_count,
+            ping_interval,
+            max_loss,
+            watch,
+            compress,
+            roam,
+            keepalive,
+            socks5,
+            throttle,
+            bind,
+            local_port,
+            mtu,
+            pad_to,
+            dscp,
+            socket_buffers,
+            bind_device,
+            listen,
+            emit_key,
+            reconnect,
+        } = opts;
+        anyhow::ensure!(!retry_interval.is_zero(), "Retry interval must be positive");
+        let probes = match ping_count {
+            Some(count) if ping_mode => {
+                anyhow::ensure!(count > 0, "Ping count must be positive");
+                Some(Some(count))
+            }
+            None if ping_mode && watch => Some(None),
+            _ => None,
+        }
+        .map(|count| PingProbes {
+            count,
+            interval: ping_interval,
+            max_loss,
+            sent: 0,
+            received: 0,
+            next_send: Instant::now(),
+            deadline: None,
+            rtts: Vec::with_capacity(count.unwrap_or(0)),
+        });
+        anyhow::ensure!(!dest_addrs.is_empty(), "No addresses to connect to");
+        if let Some(ip) = bind {
+            dest_addrs.retain(|a| a.is_ipv4() == ip.is_ipv4());
+            anyhow::ensure!(
+                !dest_addrs.is_empty(),
+                "No server addresses of the same family as local address {}",
+                ip
+            );
+        }
+        dest_addrs.sort_by_key(|a| a.is_ipv4());
+        let dest_sa = dest_addrs.remove(0);
+        let socks5 = match socks5 {
+            Some(proxy) => Some(Socks5Udp::associate(proxy, connect_timeout)?),
+            None => None,
+        };
+        let local = LocalAddr {
+            ip: bind,
+            port: local_port.unwrap_or(0),
+            dscp,
+            socket_buffers,
+            device: bind_device,
+        };
+        let client_socket = match socks5 {
+            Some(ref x) => local.bind_for(x.relay)?,
+            None => local.bind_for(dest_sa)?,
         };
         let mut sessid = [0u8; 8];
         getrandom::getrandom(&mut sessid[..])?;
         Ok(Client {
-            client_socket: UdpSocket::bind(bind_sa)?,
+            client_socket,
+            connected: false,
+            socks5,
             crypto,
+            compress,
+            pad_to,
             mosh: None,
-            past_nonces: FxHashSet::default(),
+            replay_guard: ReplayGuard::new(replay_window),
             destination_address: dest_sa,
-            resend_counter: 50,
+            remaining_addresses: dest_addrs,
+            next_resend: Instant::now(),
+            resend_interval: retry_interval,
+            address_deadline: Instant::now() + connect_timeout,
             sessid: u64::from_ne_bytes(sessid),
+            cookie: None,
             ping_mode,
+            status_mode,
+            auth_failures: 0,
+            buffer_size,
+            mosh_client_args,
+            retry_interval,
+            connect_timeout,
+            probes,
+            ping_send_times: Vec::new(),
+            ping_seq_base: 0,
+            roam,
+            last_server_activity: Instant::now(),
+            keepalive,
+            last_sent: Instant::now(),
+            throttle: throttle.map(|opts| Throttle::new(opts, buffer_size)),
+            local,
+            mtu_check: mtu.map(MtuCheck::new),
+            listen,
+            emit_key,
+            reconnect,
+            reconnecting: false,
+            session_key: None,
+            sockets_changed: true,
         })
     }
 
     pub fn connect(&mut self) {
-        let mut buf = [0u8; 8192];
-        let mut polls: Vec<PollFd> = Vec::with_capacity(2);
-        polls.push(PollFd::new(
-            self.client_socket.as_raw_fd(),
-            PollFlags::POLLIN,
-        ));
-        self.send_request(false);
+        // One extra byte to detect datagrams that do not fit
+        let mut buf = vec![0u8; self.buffer_size + 1];
+        let mut batch = RecvBatch::new(self.buffer_size + 1);
+        let mut poller = PlatformPoller::default();
+        if self.probes.is_none() {
+            self.address_deadline = Instant::now() + self.connect_timeout;
+            self.send_request(false);
+            self.schedule_resend();
+        }
         loop {
-            polls.truncate(1);
-            if let Some(ref mosh) = self.mosh {
-                polls.push(PollFd::new(mosh.socket.as_raw_fd(), PollFlags::POLLIN));
+            let timeout = if let Some(timeout) = self.ping_probes_tick() {
+                timeout
+            } else if self.mosh.is_some() {
+                self.mosh_poll_timeout()
+            } else {
+                let wakeup = self.next_resend.min(self.address_deadline);
+                let timeout = wakeup.saturating_duration_since(Instant::now()).as_millis() + 1;
+                timeout.min(i32::MAX as u128) as i32
+            };
+            if self.sockets_changed {
+                self.sockets_changed = false;
+                let ret = match self.mosh {
+                    Some(ref mosh) => poller.set_sockets(&[&self.client_socket, &mosh.socket]),
+                    None => poller.set_sockets(&[&self.client_socket]),
+                };
+                if let Err(e) = ret {
+                    error!("Cannot watch sockets: {}", e);
+                    return;
+                }
             }
-
-            let timeout = if self.mosh.is_some() { -1 } else { 200 };
-            match poll(&mut polls[..], timeout) {
+            match poller.poll(timeout) {
+                // e.g. SIGWINCH or SIGCHLD, not a reason to stop
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
                 Err(e) => {
-                    eprintln!("poll error: {}", e);
+                    error!("poll error: {}", e);
                     return;
                 }
-                Ok(n) => {
-                    if n == 0 {
-                        if self.resend_counter > 0 {
-                            self.resend_counter -= 1;
-                            self.send_request(false);
-                        } else if self.mosh.is_none() {
-                            eprintln!("Failed to receive usable reply from server");
-                            std::process::exit(2);
-                        }
+                Ok(_) => {
+                    if self.probes.is_none() && self.mosh.is_none() {
+                        self.handshake_tick();
                     }
                 }
             }
+            if let Some(ref mut mosh) = self.mosh {
+                mosh.relearn_tick();
+            }
+            let silent = self.mosh.is_some() && self.last_server_activity.elapsed() >= ROAM_TIMEOUT;
+            if silent && self.roam.is_some() {
+                self.reresolve_server();
+            }
+            if silent && self.reconnect && !self.reconnecting {
+                self.start_reconnect();
+            } else if self.reconnecting && Instant::now() >= self.next_resend {
+                self.send_request(false);
+                self.schedule_resend();
+            }
+            while let Some(pkt) = self.throttle.as_mut().and_then(|x| x.pop_ready()) {
+                let _ = self.send_to_server(&pkt, Payload::Mosh);
+                self.last_sent = Instant::now();
+            }
+            if let Some(keepalive) = self.keepalive {
+                if self.mosh.is_some() && self.last_sent.elapsed() >= keepalive {
+                    self.send_message(&Message::Ping { seq: KEEPALIVE_SEQ });
+                }
+            }
 
-            if matches!(polls[0].revents(), Some(x) if x.contains(PollFlags::POLLIN)) {
-                let (pkt, fromaddr) = match self.client_socket.recv_from(&mut buf) {
-                    Ok((sz, fromaddr)) => (&buf[..sz], fromaddr),
-                    Err(_) => continue,
-                };
-
-                // seems like client-side address sensitivy only breaks things
-                let _ = fromaddr;
-
-                let msg = match crate::protocol::decrypt(pkt, &self.crypto, &mut self.past_nonces)
-                {
-                    Ok(x) => x,
-                    Err(_e) => {
-                        if let Some(ref mosh) = self.mosh {
-                            if let Some(reply_addr) = mosh.reply_address {
-                                if mosh.socket.send_to(pkt, reply_addr).is_err() {
-                                    eprintln!("Mosh client socket closed");
-                                    return;
-                                }
-                            } else {
-                                eprintln!("Premature traffic to mosh-client");
-                            }
-                        } else {
-                            eprintln!("Error: {}", _e);
-                        }
-                        continue;
-                    }
-                };
-
-                match msg {
-                    Message::Ping => {
-                        eprintln!("Stray incomding message: Ping");
-                    }
-                    Message::Pong => {
-                        if self.ping_mode {
-                            println!("Received Pong reply");
-                            return;
-                        }
-                    }
-                    Message::ServerStarted { key } => {
-                        if self.ping_mode {
-                            eprintln!("Unexpected reply: ServerStarted");
-                        } else if self.mosh.is_none() {
-                            let udp = match Client::start_mosh_client(key) {
-                                Ok(x) => x,
-                                Err(e) => {
-                                    eprintln!("Error starting mosh-client: {}", e);
-                                    std::process::exit(3)
-                                }
-                            };
-                            self.mosh = Some(udp);
-                        }
-                    }
-                    Message::StartServer { .. } => {
-                        eprintln!("Stray incoming message: StartServer");
-                    }
-                    Message::Failed { msg } => {
-                        eprintln!("Received error from server: {}", msg);
-                        std::process::exit(1);
-                    }
-                    Message::UpdateAddress => {
-                        self.send_request(true);
+            if poller.is_readable(0) {
+                if batch.recv_from(&self.client_socket).is_err() {
+                    continue;
+                }
+                for i in 0..batch.len() {
+                    let (pkt, fromaddr) = batch.get(i);
+                    if !self.handle_server_datagram(pkt, fromaddr) {
+                        return;
                     }
-                };
-
-                // end of client socket msg code
+                }
             }
-            if polls.len() >= 2
-                && matches!(polls[1].revents(), Some(x) if x.contains(PollFlags::POLLIN))
-            {
+            if poller.is_readable(1) {
                 if let Some(ref mut mosh) = self.mosh {
                     let mut clearmosh = false;
-                    let (pkt, addr) = match mosh.socket.recv_from(&mut buf) {
+                    let received = match mosh.reply_address {
+                        Some(addr) if mosh.connected => {
+                            mosh.socket.recv(&mut buf).map(|sz| (sz, addr))
+                        }
+                        _ => mosh.socket.recv_from(&mut buf),
+                    };
+                    let (pkt, addr) = match received {
                         Ok((sz, addr)) => (&buf[..sz], addr),
+                        Err(e) if is_transient(&e) => continue,
+                        // ICMP error from a datagram sent after mosh-client exited
+                        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => continue,
                         Err(_) => {
                             clearmosh = true;
                             (&buf[..], self.destination_address) // dummy value
                         }
                     };
                     if clearmosh {
-                        eprintln!("Cannot receive from mosh-client-facing socket");
-                        std::process::exit(1);
+                        error!("Cannot receive from mosh-client-facing socket");
+                        self.exit(ExitCode::LocalError);
                     } else {
-                        if mosh.reply_address.is_none() {
-                            mosh.reply_address = Some(addr);
+                        // The socket is bound to localhost, so only local processes can get here.
+                        // Another local user can hijack the session after mosh-client goes silent,
+                        // but it could also connect to mosh-client port and that is no worse.
+                        // mosh-client's port is not known until its first datagram, after that
+                        // the socket is connected to the learned address.
+                        if !mosh.connected {
+                            if mosh.loopback_only && !addr.ip().is_loopback() {
+                                warn!("Dropping datagram from non-loopback address {}", addr);
+                                continue;
+                            }
+                            if Some(addr) != mosh.reply_address {
+                                let idle =
+                                    mosh.last_reply_activity.elapsed() >= REPLY_ADDRESS_RELEARN;
+                                let relearn = mosh.reply_address.is_none()
+                                    || (addr.ip().is_loopback() && idle);
+                                if !relearn {
+                                    continue;
+                                }
+                                if let Some(old) = mosh.reply_address {
+                                    warn!("mosh-client address changed from {} to {}", old, addr);
+                                }
+                                mosh.reply_address = Some(addr);
+                            }
+                            mosh.connect_to(addr);
                         }
-                        if Some(addr) != mosh.reply_address {
+                        mosh.last_reply_activity = Instant::now();
+                        if pkt.len() > self.buffer_size {
+                            warn!(
+                                "Dropping datagram from mosh-client: larger than --buffer-size {}, may be truncated",
+                                self.buffer_size
+                            );
                             continue;
                         }
-                        let _ = self.client_socket.send_to(pkt, self.destination_address);
+                        let allowed = match self.throttle {
+                            Some(ref mut throttle) => throttle.admit(pkt),
+                            None => true,
+                        };
+                        if allowed {
+                            let _ = self.send_to_server(pkt, Payload::Mosh);
+                            self.last_sent = Instant::now();
+                        }
                     }
                 } else {
                     unreachable!()
@@ -183,55 +609,804 @@ impl Client {
         }
     }
 
-    fn send_request(&self, update_address: bool) {
+    /// Handles a datagram received on `client_socket`. Returns `false` when `connect` is done.
+    fn handle_server_datagram(&mut self, pkt: &[u8], fromaddr: SocketAddr) -> bool {
+        // Checked before SOCKS5 decapsulation, which would hide the truncation
+        if pkt.len() > self.buffer_size {
+            warn!(
+                "Dropping datagram from {}: larger than --buffer-size {}, may be truncated",
+                fromaddr, self.buffer_size
+            );
+            return true;
+        }
+        let (pkt, fromaddr) = match self.socks5 {
+            Some(ref x) if fromaddr == x.relay => match socks5::decapsulate(pkt) {
+                Some(x) => (x.1, x.0),
+                None => return true,
+            },
+            Some(_) => return true,
+            None => (pkt, fromaddr),
+        };
+
+        // seems like client-side address sensitivy only breaks things
+        if fromaddr == self.destination_address {
+            self.last_server_activity = Instant::now();
+        }
+
+        let decrypted = crate::protocol::decrypt(pkt, &self.crypto, &mut self.replay_guard);
+        trace::received(
+            &self.client_socket,
+            fromaddr,
+            pkt.len(),
+            (&decrypted).into(),
+            self.replay_guard.len(),
+        );
+        let msg = match decrypted {
+            Ok(x) => x,
+            Err(DecryptError::BadMagic) => {
+                if let Some(ref mosh) = self.mosh {
+                    if let Some(reply_addr) = mosh.reply_address {
+                        match mosh.send(pkt, reply_addr) {
+                            Ok(_) => (),
+                            Err(e) if is_transient(&e) => {
+                                debug!("Dropped datagram to mosh-client: {}", e);
+                            }
+                            // mosh-client has just exited, its waiter thread takes over
+                            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                                debug!("Dropped datagram to mosh-client: {}", e);
+                            }
+                            Err(e) => {
+                                error!("Cannot send to mosh-client: {}", e);
+                                return false;
+                            }
+                        }
+                    } else {
+                        warn!("Premature traffic to mosh-client");
+                    }
+                } else {
+                    warn!("Non-moshudp datagram from {}", fromaddr);
+                }
+                return true;
+            }
+            // Most likely duplicated by the network, the first copy was handled
+            Err(DecryptError::Replay) => {
+                debug!("Dropping replayed datagram from {}", fromaddr);
+                return true;
+            }
+            Err(e) => {
+                warn!("Undecryptable datagram from {}: {}", fromaddr, e);
+                if fromaddr == self.destination_address {
+                    self.auth_failures += 1;
+                }
+                return true;
+            }
+        };
+
+        // Only authenticated datagrams may move the server
+        if self.roam.is_some() && fromaddr != self.destination_address {
+            info!(
+                "Server moved from {} to {}",
+                self.destination_address, fromaddr
+            );
+            self.destination_address = fromaddr;
+            self.last_server_activity = Instant::now();
+        }
+
+        match msg {
+            Message::Ping { .. } => {
+                warn!("Stray incomding message: Ping");
+            }
+            Message::Pong { seq: KEEPALIVE_SEQ } => (),
+            Message::Pong { seq } => {
+                let sent_at = seq
+                    .checked_sub(self.ping_seq_base)
+                    .and_then(|i| usize::try_from(i).ok())
+                    .and_then(|i| self.ping_send_times.get_mut(i))
+                    .and_then(|x| x.take());
+                let rtt = match sent_at {
+                    Some(t) => t.elapsed(),
+                    None => {
+                        warn!("Stray or duplicate Pong reply, seq={}", seq);
+                        return true;
+                    }
+                };
+                if self.watching() {
+                    println!(
+                        "{} seq={} time={:.1} ms",
+                        timestamp(),
+                        seq,
+                        rtt.as_secs_f64() * 1000.0
+                    );
+                } else {
+                    println!(
+                        "Received Pong reply: seq={} time={:.1} ms",
+                        seq,
+                        rtt.as_secs_f64() * 1000.0
+                    );
+                }
+                if let Some(ref mut probes) = self.probes {
+                    probes.received += 1;
+                    if let Some(count) = probes.count {
+                        probes.rtts.push(rtt);
+                        if probes.sent == count && probes.received >= probes.sent {
+                            self.finish_ping_probes();
+                        }
+                    }
+                } else if self.ping_mode {
+                    return false;
+                }
+            }
+            Message::ServerStarted { key } => {
+                if self.ping_mode || self.status_mode {
+                    warn!("Unexpected reply: ServerStarted");
+                } else if self.mosh.is_none() {
+                    self.session_key = Some(key.clone());
+                    self.connect_socket();
+                    let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+                    let buffers = self.local.socket_buffers;
+                    let udp = match self.listen {
+                        Some(addr) => Client::listen_locally(addr, buffers),
+                        None if self.emit_key => Client::listen_locally(localhost, buffers),
+                        None => Client::start_mosh_client(
+                            key.clone(),
+                            &self.mosh_client_args,
+                            buffers,
+                            self.bye_sender(),
+                        )
+                        .map_err(|e| anyhow::anyhow!("Error starting mosh-client: {}", e)),
+                    };
+                    let udp = match udp {
+                        Ok(x) => x,
+                        Err(e) => {
+                            error!("{}", e);
+                            ExitCode::LocalError.exit()
+                        }
+                    };
+                    if self.emit_key {
+                        let port = udp.socket.local_addr().map_or(0, |x| x.port());
+                        println!("MOSH_KEY={}", key);
+                        println!("MOSH_PORT={}", port);
+                    }
+                    self.mosh = Some(udp);
+                    self.sockets_changed = true;
+                } else if self.reconnecting {
+                    self.reconnecting = false;
+                    if self.session_key.as_ref() != Some(&key) {
+                        error!("Server has lost the session, cannot resume it");
+                        // Stop the new mosh-server that was started for nothing
+                        self.send_message(&Message::Bye {
+                            sessid: self.sessid,
+                        });
+                        self.exit(ExitCode::ServerFailed);
+                    }
+                    info!("Reconnected to {}", self.destination_address);
+                    self.connect_socket();
+                }
+            }
+            Message::StartServer { .. } => {
+                warn!("Stray incoming message: StartServer");
+            }
+            Message::Failed { msg } => {
+                error!("Received error from server: {}", msg);
+                self.exit(ExitCode::ServerFailed);
+            }
+            Message::UpdateAddress { .. } => {
+                self.send_request(true);
+            }
+            Message::Cookie { token } => {
+                if self.reconnecting {
+                    // Server checks for the session before asking for a cookie
+                    error!("Server has lost the session, cannot resume it");
+                    self.exit(ExitCode::ServerFailed);
+                }
+                if !(self.ping_mode || self.status_mode) && self.mosh.is_none() {
+                    self.cookie = Some(token);
+                    // Server is reachable, no need to back off
+                    self.resend_interval = self.retry_interval;
+                    self.send_request(false);
+                    self.schedule_resend();
+                }
+            }
+            Message::Status => {
+                warn!("Stray incoming message: Status");
+            }
+            Message::StatusReply {
+                active_sessions,
+                uptime_secs,
+                version,
+                counters,
+            } => {
+                if self.status_mode {
+                    println!(
+                        "Server version {}, up for {} seconds, {} active sessions",
+                        version, uptime_secs, active_sessions
+                    );
+                    println!("Traffic {}", counters);
+                    return false;
+                }
+            }
+            Message::Bye { .. } => {
+                warn!("Stray incoming message: Bye");
+            }
+        }
+        true
+    }
+
+    /// Resends the request when it is due. Moves on to the next server address, or exits if
+    /// there is none, when the current one does not reply within `connect_timeout`.
+    fn handshake_tick(&mut self) {
+        let now = Instant::now();
+        if now >= self.address_deadline {
+            if !self.try_next_address() {
+                if self.auth_failures > 0 {
+                    error!(
+                        "Failed to decrypt {} replies from server, check key, cipher and moshudp version",
+                        self.auth_failures
+                    );
+                    ExitCode::AuthFailed.exit();
+                }
+                error!(
+                    "Failed to receive usable reply from server. Server does not reply to requests with wrong key, its log shows \"Possible key mismatch\" then"
+                );
+                ExitCode::Unreachable.exit();
+            }
+        } else if now >= self.next_resend {
+            self.send_request(false);
+            self.schedule_resend();
+        }
+    }
+
+    /// Schedules the next resend of the request with exponential backoff and random jitter,
+    /// so that many clients do not resend in lockstep
+    fn schedule_resend(&mut self) {
+        let mut r = [0u8; 4];
+        let _ = getrandom::getrandom(&mut r);
+        let jitter = u32::from_ne_bytes(r) as f64 / u32::MAX as f64 / 2.0;
+        self.next_resend = Instant::now() + self.resend_interval.mul_f64(1.0 + jitter);
+        self.resend_interval =
+            (self.resend_interval * 2).min(MAX_RETRY_INTERVAL.max(self.retry_interval));
+    }
+
+    /// Switch to the next candidate server address after the current one did not reply,
+    /// starting the handshake anew from a fresh socket. Returns `false` if there are no more addresses to try.
+    fn try_next_address(&mut self) -> bool {
+        while !self.remaining_addresses.is_empty() {
+            let addr = self.remaining_addresses.remove(0);
+            warn!(
+                "No reply from {}, trying {}",
+                self.destination_address, addr
+            );
+            self.destination_address = addr;
+            // Cookie is only valid for the server that issued it
+            self.cookie = None;
+            // SOCKS5 relay stays the same. Fixed `--local-port` cannot be bound again while
+            // the old socket is open, so keep it if address family does not change.
+            let ipv4 = self.client_socket.local_addr().map(|a| a.is_ipv4()).ok();
+            let keep_socket = self.local.port != 0 && ipv4 == Some(addr.is_ipv4());
+            if self.socks5.is_none() && !keep_socket {
+                match self.local.bind_for(addr) {
+                    Ok(socket) => {
+                        self.client_socket = socket;
+                        self.sockets_changed = true;
+                    }
+                    Err(e) => {
+                        warn!("Cannot create socket for {}: {}", addr, e);
+                        continue;
+                    }
+                }
+            }
+            self.address_deadline = Instant::now() + self.connect_timeout;
+            self.resend_interval = self.retry_interval;
+            self.send_request(false);
+            self.schedule_resend();
+            return true;
+        }
+        false
+    }
+
+    /// `--reconnect`: redo the handshake with the same session id, so that server hands back
+    /// the same mosh-server over whatever path works now. A fresh socket gets a new NAT mapping.
+    fn start_reconnect(&mut self) {
+        warn!(
+            "No reply from server {} for {} seconds, reconnecting",
+            self.destination_address,
+            ROAM_TIMEOUT.as_secs()
+        );
+        self.reconnecting = true;
+        self.cookie = None;
+        // Fixed `--local-port` cannot be bound again while the old socket is open
+        if self.socks5.is_none() && self.local.port == 0 {
+            match self.local.bind_for(self.destination_address) {
+                Ok(socket) => {
+                    self.client_socket = socket;
+                    self.connected = false;
+                    self.sockets_changed = true;
+                }
+                Err(e) => warn!("Cannot create new socket: {}", e),
+            }
+        }
+        self.resend_interval = self.retry_interval;
+        self.send_request(false);
+        self.schedule_resend();
+    }
+
+    /// Milliseconds until the next `--roam`, `--reconnect` or `--keepalive` check or until a datagram delayed
+    /// by `--rate-limit-queue` may be sent, or -1 for infinite poll
+    fn mosh_poll_timeout(&self) -> i32 {
+        let deadlines = [
+            self.roam
+                .as_ref()
+                .map(|_| self.last_server_activity + ROAM_TIMEOUT),
+            self.keepalive.map(|x| self.last_sent + x),
+            if self.reconnecting {
+                Some(self.next_resend)
+            } else {
+                self.reconnect
+                    .then(|| self.last_server_activity + ROAM_TIMEOUT)
+            },
+            self.throttle
+                .as_ref()
+                .and_then(|x| x.next_ready_in())
+                .map(|x| Instant::now() + x),
+            self.mosh.as_ref().and_then(|x| x.relearn_deadline()),
+        ];
+        match deadlines.iter().flatten().min() {
+            Some(t) => {
+                let remaining = t.saturating_duration_since(Instant::now());
+                // round up to avoid waking up just before the deadline
+                (remaining.as_millis() + 1).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        }
+    }
+
+    /// `--roam` mode: look up server address again and switch to it if the old one is gone
+    fn reresolve_server(&mut self) {
+        self.last_server_activity = Instant::now();
+        let resolver = match self.roam {
+            Some(ref x) => x,
+            None => return,
+        };
+        let mut addrs = match resolver() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to resolve server address again: {}", e);
+                return;
+            }
+        };
+        if addrs.contains(&self.destination_address) {
+            return;
+        }
+        // Prefer the same address family to keep the socket
+        let ipv4 = self.destination_address.is_ipv4();
+        addrs.sort_by_key(|a| a.is_ipv4() != ipv4);
+        let addr = match addrs.first() {
+            Some(x) => *x,
+            None => return,
+        };
+        if addr.is_ipv4() != ipv4 && self.socks5.is_none() {
+            match self.local.bind_for(addr) {
+                Ok(socket) => {
+                    self.client_socket = socket;
+                    self.sockets_changed = true;
+                }
+                Err(e) => {
+                    warn!("Cannot create socket for {}: {}", addr, e);
+                    return;
+                }
+            }
+        }
+        info!(
+            "Server address changed from {} to {}",
+            self.destination_address, addr
+        );
+        self.destination_address = addr;
+        // Let server know our new address in case the socket changed
+        self.send_request(true);
+    }
+
+    /// Sends due ping of `--count` or `--watch` mode and returns poll timeout until the next
+    /// ping or until giving up on replies. Returns `None` if not in either mode.
+    fn ping_probes_tick(&mut self) -> Option<i32> {
+        let now = Instant::now();
+        let connect_timeout = self.connect_timeout;
+        if self.watching() {
+            self.report_lost_pings(now);
+        }
+        let probes = self.probes.as_mut()?;
+        let mut send = false;
+        if probes.count != Some(probes.sent) && now >= probes.next_send {
+            probes.sent += 1;
+            probes.next_send = now + probes.interval;
+            if Some(probes.sent) == probes.count {
+                probes.deadline = Some(now + connect_timeout);
+            }
+            send = true;
+        }
+        let wakeup = match probes.deadline {
+            Some(deadline) if now >= deadline => {
+                self.finish_ping_probes();
+            }
+            Some(deadline) => deadline,
+            None => probes.next_send,
+        };
+        if send {
+            self.send_request(false);
+        }
+        let timeout = wakeup.saturating_duration_since(now).as_millis() + 1;
+        Some(timeout.min(i32::MAX as u128) as i32)
+    }
+
+    fn watching(&self) -> bool {
+        matches!(self.probes, Some(PingProbes { count: None, .. }))
+    }
+
+    /// Prints a line for each ping of `--watch` mode left without reply for `connect_timeout`
+    /// and forgets it, so that memory use stays bounded
+    fn report_lost_pings(&mut self, now: Instant) {
+        for (i, sent_at) in self.ping_send_times.iter_mut().enumerate() {
+            match *sent_at {
+                Some(t) if now.saturating_duration_since(t) >= self.connect_timeout => {
+                    println!("{} seq={} lost", timestamp(), self.ping_seq_base + i as u64);
+                    *sent_at = None;
+                }
+                _ => (),
+            }
+        }
+        let done = self
+            .ping_send_times
+            .iter()
+            .position(Option::is_some)
+            .unwrap_or(self.ping_send_times.len());
+        self.ping_send_times.drain(..done);
+        self.ping_seq_base += done as u64;
+    }
+
+    /// Prints summary of `--count` ping mode and exits
+    fn finish_ping_probes(&self) -> ! {
+        let probes = self.probes.as_ref().unwrap();
+        let lost = probes.sent - probes.received.min(probes.sent);
+        let loss = 100.0 * lost as f64 / probes.sent as f64;
+        println!(
+            "{} pings sent, {} replies received, {:.1}% loss",
+            probes.sent, probes.received, loss
+        );
+        if !probes.rtts.is_empty() {
+            let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+            let min = probes.rtts.iter().copied().min().unwrap();
+            let max = probes.rtts.iter().copied().max().unwrap();
+            let avg = probes.rtts.iter().sum::<Duration>() / probes.rtts.len() as u32;
+            println!(
+                "rtt min/avg/max = {:.1}/{:.1}/{:.1} ms",
+                ms(min),
+                ms(avg),
+                ms(max)
+            );
+        }
+        if probes.received == 0 || loss > probes.max_loss {
+            ExitCode::Unreachable.exit();
+        }
+        ExitCode::Success.exit();
+    }
+
+    fn send_request(&mut self, update_address: bool) {
         let msg = match (update_address, self.ping_mode) {
-            (true, _) => Message::UpdateAddress,
-            (false, true) => Message::Ping,
+            (true, _) => Message::UpdateAddress {
+                sessid: self.sessid,
+            },
+            (false, _) if self.status_mode => Message::Status,
+            (false, true) => {
+                self.ping_send_times.push(Some(Instant::now()));
+                Message::Ping {
+                    seq: self.ping_seq_base + (self.ping_send_times.len() - 1) as u64,
+                }
+            }
             (false, false) => Message::StartServer {
                 sessid: self.sessid,
+                cookie: self.cookie,
             },
         };
-        
-        let pkt = crate::protocol::encrypt(&msg, &self.crypto).unwrap();
-        if let Err(e) = self.client_socket.send_to(&pkt, self.destination_address) {
-            eprintln!("sendto: {}", e);
-            std::process::exit(3);
+        self.send_message(&msg);
+    }
+
+    /// Stops mosh-client, if it is running, and exits. `ExitCode::exit` skips destructors.
+    fn exit(&mut self, code: ExitCode) -> ! {
+        self.mosh = None;
+        code.exit()
+    }
+
+    fn send_message(&mut self, msg: &Message) {
+        let pkt = match crate::protocol::encrypt(msg, &self.crypto, self.compress, self.pad_to) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to encrypt request: {}", e);
+                return;
+            }
+        };
+        match self.send_to_server(&pkt, Payload::Message(msg)) {
+            Ok(_) => (),
+            // ICMP port unreachable from earlier datagram, reported on connected socket
+            Err(e) if self.connected && e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                warn!("Server {} refused datagram", self.destination_address);
+            }
+            Err(e) if is_transient(&e) => {
+                debug!("Dropped datagram to server: {}", e);
+            }
+            // e.g. network is unreachable while switching networks
+            Err(e) if (self.reconnect && self.mosh.is_some()) || self.watching() => {
+                warn!("sendto: {}", e);
+            }
+            Err(e) => {
+                error!("sendto: {}", e);
+                self.exit(ExitCode::LocalError);
+            }
         }
+        self.last_sent = Instant::now();
     }
 
-    fn start_mosh_client(key: String) -> anyhow::Result<MoshClientState> {
-        let udp = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)))?;
-        let port = udp.local_addr()?.port();
-        let mosh_client =
-            std::env::var_os("MOSH_CLIENT").unwrap_or_else(||OsStr::from_bytes(b"mosh-client").to_owned());
-        let mut cmd = std::process::Command::new(mosh_client);
-        cmd.arg("127.0.0.1").arg(format!("{}", port));
-        cmd.env("MOSH_KEY", key);
-        let mut child = cmd.spawn()?;
-        std::thread::spawn(move || match child.wait() {
-            Ok(c) => {
-                if c.success() {
-                    std::process::exit(0);
+    /// Let kernel filter datagrams by server address once the server is known. Not done with
+    /// `--roam`, which accepts server from new addresses, or with `--socks5`.
+    fn connect_socket(&mut self) {
+        if self.roam.is_some() || self.socks5.is_some() {
+            return;
+        }
+        match self.client_socket.connect(self.destination_address) {
+            Ok(()) => self.connected = true,
+            Err(e) => warn!(
+                "Cannot connect socket to {}: {}",
+                self.destination_address, e
+            ),
+        }
+    }
+
+    /// Send datagram to `destination_address`, directly or through SOCKS5 relay
+    fn send_to_server(&self, pkt: &[u8], payload: Payload) -> std::io::Result<usize> {
+        trace::sent(
+            &self.client_socket,
+            self.destination_address,
+            pkt.len(),
+            payload,
+        );
+        match self.socks5 {
+            Some(ref x) => {
+                let encapsulated = socks5::encapsulate(self.destination_address, pkt);
+                if let Some(ref mtu_check) = self.mtu_check {
+                    mtu_check.check(encapsulated.len(), x.relay);
+                }
+                self.client_socket.send_to(&encapsulated, x.relay)
+            }
+            None => {
+                if let Some(ref mtu_check) = self.mtu_check {
+                    mtu_check.check(pkt.len(), self.destination_address);
+                }
+                if self.connected {
+                    self.client_socket.send(pkt)
                 } else {
-                    eprintln!("Unsuccessful exit status of mosh-client: {}", c);
-                    std::process::exit(4);
+                    self.client_socket.send_to(pkt, self.destination_address)
                 }
             }
-            Err(_e) => {
-                eprintln!("Failed waiting for mosh-client child process");
-                std::process::exit(3);
+        }
+    }
+
+    /// Sends `Bye` from another thread, for when mosh-client exits. Uses server address
+    /// known at the time of the call.
+    fn bye_sender(&self) -> impl FnOnce() + Send + 'static {
+        let socket = self.client_socket.try_clone();
+        let crypto = self.crypto.clone();
+        let (compress, pad_to) = (self.compress, self.pad_to);
+        let (destination, connected) = (self.destination_address, self.connected);
+        let relay = self.socks5.as_ref().map(|x| x.relay);
+        let msg = Message::Bye {
+            sessid: self.sessid,
+        };
+        move || {
+            let socket = match socket {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            let pkt = match crate::protocol::encrypt(&msg, &crypto, compress, pad_to) {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            trace::sent(&socket, destination, pkt.len(), Payload::Message(&msg));
+            let _ = match relay {
+                Some(relay) => socket.send_to(&socks5::encapsulate(destination, &pkt), relay),
+                None if connected => socket.send(&pkt),
+                None => socket.send_to(&pkt, destination),
+            };
+        }
+    }
+
+    /// `--listen` relay mode: the local peer is whoever sends to `addr` first
+    fn listen_locally(addr: SocketAddr, buffers: SocketBuffers) -> anyhow::Result<MoshClientState> {
+        let udp = UdpSocket::bind(addr)
+            .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", addr, e))?;
+        buffers.apply(&udp, "local relay socket")?;
+        info!("Forwarding traffic of {}", udp.local_addr()?);
+        Ok(MoshClientState {
+            socket: udp,
+            reply_address: None,
+            last_reply_activity: Instant::now(),
+            loopback_only: addr.ip().is_loopback(),
+            child: None,
+            connected: false,
+        })
+    }
+
+    /// `on_exit` is called when mosh-client exits, before exiting the process
+    fn start_mosh_client(
+        key: String,
+        extra_args: &[String],
+        buffers: SocketBuffers,
+        on_exit: impl FnOnce() + Send + 'static,
+    ) -> anyhow::Result<MoshClientState> {
+        let udp = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)))?;
+        buffers.apply(&udp, "mosh-client socket")?;
+        let port = udp.local_addr()?.port();
+        let mut cmd = mosh_client_command(&key, extra_args, &port.to_string())?;
+        let child = Arc::new(Mutex::new(Some(cmd.spawn()?)));
+        let waited = child.clone();
+        // Polls instead of blocking in `wait`, so that `MoshClientProcess::stop` can take
+        // the child away
+        std::thread::spawn(move || loop {
+            let status = match waited.lock().as_deref_mut() {
+                Ok(Some(child)) => child.try_wait(),
+                // Stopped by the client, which exits on its own
+                _ => return,
+            };
+            match status {
+                Ok(None) => std::thread::sleep(MOSH_CLIENT_WAIT_INTERVAL),
+                Ok(Some(c)) => {
+                    on_exit();
+                    if c.success() {
+                        ExitCode::Success.exit();
+                    } else {
+                        error!("Unsuccessful exit status of mosh-client: {}", c);
+                        std::process::exit(mosh_client_exit_code(c));
+                    }
+                }
+                Err(_e) => {
+                    error!("Failed waiting for mosh-client child process");
+                    ExitCode::LocalError.exit();
+                }
             }
         });
         Ok(MoshClientState {
-            //child,
             socket: udp,
             reply_address: None,
+            last_reply_activity: Instant::now(),
+            loopback_only: true,
+            child: Some(MoshClientProcess(child)),
+            connected: false,
         })
     }
 }
 
+/// Exit code of `connect` mirroring unsuccessful `status` of mosh-client, like a shell would
+/// report it
+fn mosh_client_exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    ExitCode::MoshClientFailed as i32
+}
+
+/// Path of mosh-client that [`Client`] starts: `MOSH_CLIENT` or `mosh-client` found in `PATH`
+pub fn find_mosh_client() -> anyhow::Result<PathBuf> {
+    let mosh_client =
+        std::env::var_os("MOSH_CLIENT").unwrap_or_else(|| OsString::from("mosh-client"));
+    crate::exe::ensure_exists(&mosh_client, "MOSH_CLIENT")
+}
+
+/// mosh-client connecting to the relay socket at `port` of 127.0.0.1
+fn mosh_client_command(key: &str, extra_args: &[String], port: &str) -> anyhow::Result<Command> {
+    let mut cmd = Command::new(find_mosh_client()?);
+    cmd.args(extra_args);
+    cmd.arg("127.0.0.1").arg(port);
+    cmd.env("MOSH_KEY", key);
+    Ok(cmd)
+}
+
+/// `connect --dry-run`: prints where [`Client`] would send its request and what it would run
+/// once the server replies, without binding sockets or starting anything
+pub fn dry_run(mut dest_addrs: Vec<SocketAddr>, opts: &ClientOptions) -> anyhow::Result<()> {
+    if let Some(ip) = opts.bind {
+        dest_addrs.retain(|a| a.is_ipv4() == ip.is_ipv4());
+    }
+    anyhow::ensure!(!dest_addrs.is_empty(), "No addresses to connect to");
+    dest_addrs.sort_by_key(|a| a.is_ipv4());
+    let dest: Vec<String> = dest_addrs.iter().map(|x| x.to_string()).collect();
+    println!("Would connect to {}", dest.join(", then "));
+    if let Some(proxy) = opts.socks5 {
+        println!("Would go through SOCKS5 proxy {}", proxy);
+    }
+    if opts.ping_mode {
+        println!("Would ping the server");
+    } else if opts.status_mode {
+        println!("Would query status of the server");
+    } else if let Some(listen) = opts.listen {
+        println!(
+            "Would relay traffic of the application sending to {}",
+            listen
+        );
+    } else if opts.emit_key {
+        println!("Would print MOSH_KEY and MOSH_PORT for a separately started mosh-client");
+    } else {
+        // The key comes from the server, the port is picked when the relay socket is bound
+        let cmd = mosh_client_command("KEY", &opts.mosh_client_args, "PORT")?;
+        println!("Would run {}", crate::exe::describe_command(&cmd));
+    }
+    Ok(())
+}
+
+/// `--bind` and `--local-port` settings
+struct LocalAddr {
+    ip: Option<IpAddr>,
+    /// 0 for ephemeral port
+    port: u16,
+    dscp: Option<u8>,
+    socket_buffers: SocketBuffers,
+    device: Option<String>,
+}
+
+impl LocalAddr {
+    /// Socket to send datagrams to `dest_sa` from
+    fn bind_for(&self, dest_sa: SocketAddr) -> anyhow::Result<UdpSocket> {
+        let ip = match (self.ip, dest_sa) {
+            (Some(ip), _) if ip.is_ipv4() != dest_sa.is_ipv4() => anyhow::bail!(
+                "Local address {} and destination {} are of different address families",
+                ip,
+                dest_sa
+            ),
+            (Some(ip), _) => ip,
+            (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let bind_sa = match (ip, dest_sa) {
+            // Link-local `--bind` address is only valid on the destination's interface
+            (IpAddr::V6(ip), SocketAddr::V6(dest)) if crate::addr::is_link_local(&ip) => {
+                SocketAddr::V6(SocketAddrV6::new(ip, self.port, 0, dest.scope_id()))
+            }
+            _ => SocketAddr::new(ip, self.port),
+        };
+        let socket = match UdpSocket::bind(bind_sa) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && self.port != 0 => {
+                anyhow::bail!("Local port {} is already in use", self.port)
+            }
+            Err(e) => anyhow::bail!("Cannot bind to {}: {}", bind_sa, e),
+        };
+        if let Some(dscp) = self.dscp {
+            crate::qos::set_dscp(&socket, dscp)
+                .map_err(|e| anyhow::anyhow!("Cannot set DSCP: {}", e))?;
+        }
+        self.socket_buffers.apply(&socket, "socket to server")?;
+        if let Some(ref ifname) = self.device {
+            crate::device::bind_to_device(&socket, ifname)?;
+        }
+        Ok(socket)
+    }
+}
+
+/// Unix time with milliseconds, prefixed to `--watch` output lines
+fn timestamp() -> String {
+    let t = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", t.as_secs(), t.subsec_millis())
+}
+
 impl Drop for MoshClientState {
     fn drop(&mut self) {
-        //let _ = self.child.wait();
+        if let Some(ref child) = self.child {
+            child.stop();
+        }
     }
 }
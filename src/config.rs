@@ -0,0 +1,88 @@
+//! `--config` file of `serve` and `connect`.
+//!
+//! The file is a flat TOML table keyed by long option names, e.g. `buffer-size = 4096` or
+//! `mosh-server-arg = ["--", "tmux"]`. Its entries are turned into command line options and
+//! inserted before the ones actually given, so that argh parses both in one go. Options given
+//! on the command line replace those from the file entirely, including repeatable ones.
+
+use std::path::Path;
+
+/// Subcommands that accept `--config`
+const SUBCOMMANDS: &[&str] = &["serve", "connect"];
+
+/// `args` (including program name) with options from `--config` file inserted after the
+/// subcommand. Returned unchanged if there is no `--config`.
+pub fn expand_args(args: Vec<String>) -> anyhow::Result<Vec<String>> {
+    // Top-level options are switches, so subcommand is the first word that is not an option
+    let subcommand = match args.iter().skip(1).position(|x| !x.starts_with('-')) {
+        Some(i) if SUBCOMMANDS.contains(&args[i + 1].as_str()) => i + 1,
+        _ => return Ok(args),
+    };
+    let path = match args[subcommand..].iter().position(|x| x == "--config") {
+        Some(i) => match args.get(subcommand + i + 1) {
+            Some(path) => path,
+            // Let argh report missing value
+            None => return Ok(args),
+        },
+        None => return Ok(args),
+    };
+    let given = &args[subcommand + 1..];
+    let from_file = load(Path::new(path))?
+        .into_iter()
+        .filter(|(option, _)| !given.contains(option))
+        .flat_map(|(option, values)| match values {
+            // Switch
+            None => vec![option],
+            Some(values) => values
+                .into_iter()
+                .flat_map(|value| [option.clone(), value])
+                .collect(),
+        });
+    let mut expanded = args[..=subcommand].to_vec();
+    expanded.extend(from_file);
+    expanded.extend_from_slice(given);
+    Ok(expanded)
+}
+
+/// Options from file at `path`, as `--name` and its values, or `None` for switches
+fn load(path: &Path) -> anyhow::Result<Vec<(String, Option<Vec<String>>)>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Cannot read config file {}: {}", path.display(), e))?;
+    let table: toml::value::Table = toml::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path.display(), e))?;
+    let mut options = Vec::with_capacity(table.len());
+    for (key, value) in table {
+        anyhow::ensure!(
+            key != "config",
+            "{}: config files cannot include other config files",
+            path.display()
+        );
+        let option = format!("--{}", key.replace('_', "-"));
+        let values = match value {
+            toml::Value::Boolean(true) => None,
+            toml::Value::Boolean(false) => continue,
+            toml::Value::Array(items) => Some(
+                items
+                    .into_iter()
+                    .map(|x| scalar(x, path, &key))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            x => Some(vec![scalar(x, path, &key)?]),
+        };
+        options.push((option, values));
+    }
+    Ok(options)
+}
+
+fn scalar(value: toml::Value, path: &Path, key: &str) -> anyhow::Result<String> {
+    Ok(match value {
+        toml::Value::String(x) => x,
+        toml::Value::Integer(x) => x.to_string(),
+        toml::Value::Float(x) => x.to_string(),
+        _ => anyhow::bail!(
+            "{}: {} should be a string, a number, a boolean or an array of those",
+            path.display(),
+            key
+        ),
+    })
+}
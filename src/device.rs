@@ -0,0 +1,38 @@
+//! `--bind-device`: pin a socket to one network interface with `SO_BINDTODEVICE`, so that
+//! its traffic uses that interface regardless of routing tables.
+
+use std::net::UdpSocket;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn bind_to_device(socket: &UdpSocket, ifname: &str) -> anyhow::Result<()> {
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: the option value is a live byte slice and its length is passed along
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            ifname.as_ptr() as *const libc::c_void,
+            ifname.len() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EPERM) => anyhow::bail!(
+            "Cannot bind socket to device {}: requires CAP_NET_RAW or root",
+            ifname
+        ),
+        Some(libc::ENODEV) => anyhow::bail!("No network interface {}", ifname),
+        _ => anyhow::bail!("Cannot bind socket to device {}: {}", ifname, e),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn bind_to_device(_socket: &UdpSocket, _ifname: &str) -> anyhow::Result<()> {
+    anyhow::bail!("--bind-device is only supported on Linux")
+}
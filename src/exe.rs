@@ -0,0 +1,75 @@
+//! Checking that mosh programs exist before spawning them, for clearer errors than ENOENT
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Fails with a message suggesting `env_var` if `program` is not an executable file
+/// (looked up in `PATH` if it has no directory part). Returns the path found.
+pub fn ensure_exists(program: &OsStr, env_var: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        anyhow::ensure!(
+            is_executable(path),
+            "{} not found at {}; set {} or install mosh",
+            path.file_name().unwrap_or(program).to_string_lossy(),
+            path.display(),
+            env_var
+        );
+        Ok(path.to_owned())
+    } else {
+        let dirs = std::env::var_os("PATH").unwrap_or_default();
+        let found = std::env::split_paths(&dirs).find_map(|dir| {
+            let candidate = dir.join(program);
+            if is_executable(&candidate) {
+                return Some(candidate);
+            }
+            let exe = candidate.with_extension("exe");
+            (cfg!(windows) && is_executable(&exe)).then_some(exe)
+        });
+        found.ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} not found in PATH; set {} or install mosh",
+                path.display(),
+                env_var
+            )
+        })
+    }
+}
+
+/// Environment overrides, program and arguments of `cmd` as a shell command line, for `--dry-run`
+pub fn describe_command(cmd: &Command) -> String {
+    let envs = cmd
+        .get_envs()
+        .filter_map(|(k, v)| v.map(|v| format!("{}={}", k.to_string_lossy(), shell_quote(v))));
+    let argv = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(shell_quote);
+    envs.chain(argv).collect::<Vec<_>>().join(" ")
+}
+
+fn shell_quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    let plain = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.into_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match std::fs::metadata(path) {
+        Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
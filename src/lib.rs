@@ -0,0 +1,27 @@
+//! Alternative simplistic UDP-based session establisher for mosh-client and mosh-server.
+//!
+//! Wire format is in [`protocol`], event loops of both sides are in [`client`] and [`server`].
+
+pub mod addr;
+mod batch;
+pub mod client;
+mod device;
+mod exe;
+#[cfg(unix)]
+mod metrics;
+mod mtu;
+mod poller;
+pub mod protocol;
+mod qos;
+mod send_error;
+#[cfg(unix)]
+pub mod server;
+pub mod sockbuf;
+mod socks5;
+pub mod throttle;
+pub mod trace;
+
+pub use client::{Client, ClientOptions, ExitCode};
+pub use protocol::{decrypt, encrypt, Datagram, DecryptError, Message, Nonce};
+#[cfg(unix)]
+pub use server::{Server, ServerOptions};
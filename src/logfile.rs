@@ -0,0 +1,51 @@
+//! `--log-file`: log output appended to a file that is reopened after SIGHUP, for logrotate.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Log target for `env_logger`. The file is reopened before the first write after SIGHUP.
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    reopen: Arc<AtomicBool>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> anyhow::Result<LogFile> {
+        // Relative path must survive `--daemon` changing to `/`
+        let path = std::env::current_dir()?.join(path);
+        let file = append(&path)
+            .map_err(|e| anyhow::anyhow!("Cannot open log file {}: {}", path.display(), e))?;
+        let reopen = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, reopen.clone())?;
+        Ok(LogFile { path, file, reopen })
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            // Keep writing to the old file if the new one cannot be opened
+            if let Ok(file) = append(&self.path) {
+                self.file = file;
+            }
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
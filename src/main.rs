@@ -1,33 +1,61 @@
 use argh::FromArgs;
-use chacha20poly1305::aead::NewAead;
 use std::{
-    net::{SocketAddr, ToSocketAddrs},
-    path::PathBuf, fs::OpenOptions, io::Write,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf, fs::OpenOptions, io::Write, time::Duration,
 };
 
+mod config;
+mod logfile;
+
 /// mosh-server and mosh-client interconnector based on UDP and a static key file
 #[derive(FromArgs)]
 struct Opts {
+    /// log debug messages. RUST_LOG environment variable can also be used to set log level
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+
+    /// log every datagram exchanged with the peer: direction, addresses, size and message,
+    /// with mosh session keys redacted
+    #[argh(switch)]
+    trace: bool,
+
+    /// append log messages to this file instead of stderr. It is reopened on SIGHUP, e.g. after logrotate
+    #[argh(option)]
+    log_file: Option<PathBuf>,
+
     #[argh(subcommand)]
     cmd: Cmd,
 }
 
+// Parsed once, size does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(FromArgs)]
 #[argh(subcommand)]
 enum Cmd {
+    #[cfg(unix)]
     Serve(Serve),
     Connect(Connect),
     Keygen(Keygen),
+    Check(Check),
 }
 
 /// server mode
+#[cfg(unix)]
 #[derive(FromArgs)]
 #[argh(subcommand, name = "serve")]
 struct Serve {
-    /// socket address to listen
+    /// socket address to listen, ignored if sockets are passed by systemd or inetd
     #[argh(positional)]
     addr: String,
 
+    /// read options from this TOML file with long option names as keys, e.g. buffer-size = 4096. Options given on the command line take precedence
+    #[argh(option)]
+    config: Option<PathBuf>,
+
+    /// additional socket address to listen on. Can be repeated
+    #[argh(option)]
+    listen: Vec<String>,
+
     /// limit hostname resolution to IPv4 addresses
     #[argh(switch, short = '4')]
     ipv4: bool,
@@ -36,9 +64,205 @@ struct Serve {
     #[argh(switch, short = '6')]
     ipv6: bool,
 
-    /// 32-byte file to generate use as a key
+    /// 32-byte file to generate use as a key, raw or base64-encoded
     #[argh(positional)]
-    keyfile: PathBuf,
+    keyfile: Option<PathBuf>,
+
+    /// environment variable to read base64-encoded key from, instead of keyfile
+    #[argh(option)]
+    key_env: Option<String>,
+
+    /// read base64-encoded key from the first line of stdin, instead of keyfile
+    #[argh(switch)]
+    key_stdin: bool,
+
+    /// additional keyfile to accept during key rotation, replies use the key the client used. Can be repeated
+    #[argh(option)]
+    accept_key: Vec<PathBuf>,
+
+    /// refuse keyfile readable by group or others instead of just warning
+    #[argh(switch)]
+    strict_perms: bool,
+
+    /// derive key from this passphrase instead of keyfile. Visible to other local users, prefer --passphrase-env
+    #[argh(option)]
+    passphrase: Option<String>,
+
+    /// environment variable to read passphrase from, instead of keyfile
+    #[argh(option)]
+    passphrase_env: Option<String>,
+
+    /// salt for deriving key from passphrase. Must be the same on client and server
+    #[argh(option, default = "DEFAULT_SALT.to_owned()")]
+    salt: String,
+
+    /// size of buffer for receiving datagrams, in bytes. Larger datagrams are dropped
+    #[argh(option, default = "DEFAULT_BUFFER_SIZE")]
+    buffer_size: usize,
+
+    /// how long to remember nonces of received datagrams for replay protection, in seconds
+    #[argh(option, default = "DEFAULT_REPLAY_WINDOW")]
+    replay_window: u64,
+
+    /// remember at most this many nonces, forgetting oldest ones before the end of --replay-window. Bounds memory use at the cost of replay protection under heavy load
+    #[argh(option)]
+    max_nonces: Option<usize>,
+
+    /// file to save nonces of received datagrams to every 10 seconds and load them from on start, to keep replay protection across restarts
+    #[argh(option)]
+    nonce_store: Option<PathBuf>,
+
+    /// AEAD cipher to use, xchacha20poly1305 (default) or aes256gcm. Must be the same on client and server
+    #[argh(option, default = "CipherKind::XChaCha20Poly1305")]
+    cipher: CipherKind,
+
+    /// how to make nonces of outgoing datagrams: random (default) or counter, a per-process random prefix and counter that never repeats within the process. Peers need not agree
+    #[argh(option, default = "NonceScheme::Random")]
+    nonce_scheme: NonceScheme,
+
+    /// magic number of moshudp datagrams in hex, to reject datagrams of other deployments before decryption. Must be the same on client and server
+    #[argh(option)]
+    magic: Option<String>,
+
+    /// derive the magic number from this name instead of specifying it with --magic
+    #[argh(option)]
+    namespace: Option<String>,
+
+    /// compress control messages with zstd. Requires `compression` feature
+    #[argh(switch)]
+    compress: bool,
+
+    /// pad control messages to a multiple of this many bytes, to hide their length
+    #[argh(option)]
+    pad_to: Option<usize>,
+
+    /// DSCP value (0-63) to mark outgoing datagrams with, e.g. 46 for expedited forwarding
+    #[argh(option)]
+    dscp: Option<u8>,
+
+    /// kernel receive buffer size (SO_RCVBUF) of UDP sockets in bytes, for high bandwidth-delay paths. May be clamped by system limits
+    #[argh(option)]
+    rcvbuf: Option<usize>,
+
+    /// kernel send buffer size (SO_SNDBUF) of UDP sockets in bytes. May be clamped by system limits
+    #[argh(option)]
+    sndbuf: Option<usize>,
+
+    /// send and receive only through this network interface (SO_BINDTODEVICE), regardless of routing tables. Linux only, requires CAP_NET_RAW or root
+    #[argh(option)]
+    bind_device: Option<String>,
+
+    /// additional argument for mosh-server, after `new -i 127.0.0.1 -p 0`. Can be repeated
+    #[argh(option)]
+    mosh_server_arg: Vec<String>,
+
+    /// command for mosh-server to run in the session instead of login shell, one argument per occurrence
+    #[argh(option)]
+    command: Vec<String>,
+
+    /// program to run instead of mosh-server, one argument per occurrence. {{ip}} and {{port}} are replaced with loopback address and a free port
+    #[argh(option)]
+    spawn_cmd: Vec<String>,
+
+    /// beginning of the line with port and key in the program output
+    #[argh(option, default = "\"MOSH CONNECT\".to_owned()")]
+    connect_line_prefix: String,
+
+    /// index of whitespace-separated word with port in the connect line, counting from 0
+    #[argh(option, default = "2")]
+    connect_line_port_field: usize,
+
+    /// index of whitespace-separated word with key in the connect line, counting from 0
+    #[argh(option, default = "3")]
+    connect_line_key_field: usize,
+
+    /// kill mosh-server and report failure to client if it does not start in this many seconds. Other clients are not served while waiting
+    #[argh(option, default = "10")]
+    spawn_timeout: u64,
+
+    /// UDP port range for mosh-server to listen on, LOW:HIGH. Any free port by default
+    #[argh(option)]
+    mosh_port_range: Option<PortRange>,
+
+    /// loopback address family for mosh-server to listen on, v4 (default) or v6
+    #[argh(option, default = "Loopback::V4")]
+    mosh_loopback: Loopback,
+
+    /// address of mosh-server instead of --mosh-loopback, e.g. in a container. Passed to mosh-server as -i and to --spawn-cmd as {{ip}}, mosh traffic is forwarded to it
+    #[argh(option)]
+    backend_addr: Option<IpAddr>,
+
+    /// forward traffic of every session to this UDP address, or to a Unix datagram socket given as unix:PATH, instead of starting mosh-server, for wrapping another UDP service
+    #[argh(option)]
+    forward_to: Option<Backend>,
+
+    /// stop mosh-server of a session after this many seconds without traffic
+    #[argh(option)]
+    idle_timeout: Option<u64>,
+
+    /// stop mosh-server of a session if its client sends no mosh traffic within this many seconds after the handshake
+    #[argh(option)]
+    handshake_timeout: Option<u64>,
+
+    /// refuse to start more than this many mosh-servers at a time
+    #[argh(option)]
+    max_sessions: Option<usize>,
+
+    /// serve only one session and exit when its mosh-server exits
+    #[argh(switch)]
+    once: bool,
+
+    /// warn if a datagram to client exceeds this many bytes including IP and UDP headers
+    #[argh(option)]
+    mtu: Option<usize>,
+
+    /// log packet, byte, decryption failure and replay counters every this many seconds
+    #[argh(option)]
+    stats_interval: Option<u64>,
+
+    /// serve the same counters and number of sessions for Prometheus at http://ADDR/metrics, e.g. 127.0.0.1:9100
+    #[argh(option)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// limit decryption attempts per second from each source address that has no session
+    #[argh(option)]
+    max_rate: Option<f64>,
+
+    /// limit forwarded mosh traffic towards the peer to this many bytes per second
+    #[argh(option)]
+    rate_limit: Option<u64>,
+
+    /// with --rate-limit, delay up to this many datagrams when over the limit instead of dropping them
+    #[argh(option, default = "0")]
+    rate_limit_queue: usize,
+
+    /// use listening sockets passed by systemd socket activation. Autodetected from LISTEN_PID
+    #[argh(switch)]
+    systemd: bool,
+
+    /// use the UDP socket passed by inetd as standard input (`dgram udp wait` in inetd.conf). Combine with --once to exit after one session
+    #[argh(switch)]
+    inetd: bool,
+
+    /// fork into background after opening listening sockets. Log messages are discarded unless --log-file is given
+    #[argh(switch)]
+    daemon: bool,
+
+    /// with --daemon, write pid of the background process to this file
+    #[argh(option)]
+    pidfile: Option<PathBuf>,
+
+    /// switch to this user after opening listening sockets. mosh-servers are started as this user
+    #[argh(option)]
+    user: Option<String>,
+
+    /// switch to this group after opening listening sockets, instead of primary group of --user
+    #[argh(option)]
+    group: Option<String>,
+
+    /// print the addresses to listen on and the mosh-server command line, then exit without binding or starting anything
+    #[argh(switch)]
+    dry_run: bool,
 }
 
 /// client mode
@@ -49,6 +273,10 @@ struct Connect {
     #[argh(positional)]
     addr: String,
 
+    /// read options from this TOML file with long option names as keys, e.g. buffer-size = 4096. Options given on the command line take precedence
+    #[argh(option)]
+    config: Option<PathBuf>,
+
     /// limit hostname resolution to IPv4 addresses
     #[argh(switch, short = '4')]
     ipv4: bool,
@@ -57,71 +285,752 @@ struct Connect {
     #[argh(switch, short = '6')]
     ipv6: bool,
 
-    /// 32-byte file to generate use as a key
+    /// 32-byte file to generate use as a key, raw or base64-encoded
     #[argh(positional)]
-    keyfile: PathBuf,
+    keyfile: Option<PathBuf>,
+
+    /// environment variable to read base64-encoded key from, instead of keyfile
+    #[argh(option)]
+    key_env: Option<String>,
+
+    /// read base64-encoded key from the first line of stdin, instead of keyfile
+    #[argh(switch)]
+    key_stdin: bool,
+
+    /// refuse keyfile readable by group or others instead of just warning
+    #[argh(switch)]
+    strict_perms: bool,
+
+    /// derive key from this passphrase instead of keyfile. Visible to other local users, prefer --passphrase-env
+    #[argh(option)]
+    passphrase: Option<String>,
+
+    /// environment variable to read passphrase from, instead of keyfile
+    #[argh(option)]
+    passphrase_env: Option<String>,
+
+    /// salt for deriving key from passphrase. Must be the same on client and server
+    #[argh(option, default = "DEFAULT_SALT.to_owned()")]
+    salt: String,
 
     /// skip most of the algorithm, just send a ping
     #[argh(switch)]
     ping: bool,
+
+    /// print server version, uptime, number of sessions and traffic counters instead of starting mosh
+    #[argh(switch)]
+    status: bool,
+
+    /// in ping mode, send this many pings and print packet loss summary
+    #[argh(option)]
+    count: Option<usize>,
+
+    /// in ping mode, keep pinging until killed, printing a timestamped line per reply or lost ping, e.g. for monitoring
+    #[argh(switch)]
+    watch: bool,
+
+    /// in ping mode with --count or --watch, delay between pings in milliseconds
+    #[argh(option, default = "1000")]
+    interval: u64,
+
+    /// in ping mode with --count, exit with error if more than this percentage of pings are lost
+    #[argh(option, default = "100.0")]
+    max_loss: f64,
+
+    /// size of buffer for receiving datagrams, in bytes. Larger datagrams are dropped
+    #[argh(option, default = "DEFAULT_BUFFER_SIZE")]
+    buffer_size: usize,
+
+    /// how long to remember nonces of received datagrams for replay protection, in seconds
+    #[argh(option, default = "DEFAULT_REPLAY_WINDOW")]
+    replay_window: u64,
+
+    /// AEAD cipher to use, xchacha20poly1305 (default) or aes256gcm. Must be the same on client and server
+    #[argh(option, default = "CipherKind::XChaCha20Poly1305")]
+    cipher: CipherKind,
+
+    /// how to make nonces of outgoing datagrams: random (default) or counter, a per-process random prefix and counter that never repeats within the process. Peers need not agree
+    #[argh(option, default = "NonceScheme::Random")]
+    nonce_scheme: NonceScheme,
+
+    /// magic number of moshudp datagrams in hex, to reject datagrams of other deployments before decryption. Must be the same on client and server
+    #[argh(option)]
+    magic: Option<String>,
+
+    /// derive the magic number from this name instead of specifying it with --magic
+    #[argh(option)]
+    namespace: Option<String>,
+
+    /// compress control messages with zstd. Requires `compression` feature
+    #[argh(switch)]
+    compress: bool,
+
+    /// pad control messages to a multiple of this many bytes, to hide their length
+    #[argh(option)]
+    pad_to: Option<usize>,
+
+    /// DSCP value (0-63) to mark outgoing datagrams with, e.g. 46 for expedited forwarding
+    #[argh(option)]
+    dscp: Option<u8>,
+
+    /// kernel receive buffer size (SO_RCVBUF) of UDP sockets in bytes, for high bandwidth-delay paths. May be clamped by system limits
+    #[argh(option)]
+    rcvbuf: Option<usize>,
+
+    /// kernel send buffer size (SO_SNDBUF) of UDP sockets in bytes. May be clamped by system limits
+    #[argh(option)]
+    sndbuf: Option<usize>,
+
+    /// send and receive only through this network interface (SO_BINDTODEVICE), regardless of routing tables. Linux only, requires CAP_NET_RAW or root
+    #[argh(option)]
+    bind_device: Option<String>,
+
+    /// additional argument for mosh-client, before host and port. Can be repeated
+    #[argh(option)]
+    mosh_client_arg: Vec<String>,
+
+    /// instead of starting mosh-client, forward traffic between the server and whatever sends to this local UDP address. For use with serve --forward-to
+    #[argh(option)]
+    listen: Option<SocketAddr>,
+
+    /// instead of starting mosh-client, print MOSH_KEY=key and MOSH_PORT=port lines and relay traffic of mosh-client started separately against 127.0.0.1 (or --listen address) and that port
+    #[argh(switch)]
+    emit_key: bool,
+
+    /// give up on each resolved server address (IPv6 first) if it does not reply in this many seconds
+    #[argh(option, default = "10")]
+    connect_timeout: u64,
+
+    /// resend request to server if there is no reply in this many milliseconds, doubling the delay with each resend up to 3 seconds
+    #[argh(option, default = "200")]
+    retry_interval: u64,
+
+    /// follow server to a new address, resolving the hostname again when server goes silent
+    #[argh(switch)]
+    roam: bool,
+
+    /// when server goes silent during a session, redo the handshake from a new socket to resume the same session, e.g. after switching networks
+    #[argh(switch)]
+    reconnect: bool,
+
+    /// ping server if nothing was sent to it for this many seconds, to keep NAT mappings alive
+    #[argh(option)]
+    keepalive: Option<u64>,
+
+    /// send datagrams through this SOCKS5 proxy (host:port) using UDP ASSOCIATE
+    #[argh(option)]
+    socks5: Option<String>,
+
+    /// local IP address to send datagrams from
+    #[argh(option)]
+    bind: Option<IpAddr>,
+
+    /// local UDP port to send datagrams from, instead of ephemeral one
+    #[argh(option)]
+    local_port: Option<u16>,
+
+    /// warn if a datagram to server exceeds this many bytes including IP and UDP headers
+    #[argh(option)]
+    mtu: Option<usize>,
+
+    /// limit forwarded mosh traffic towards the peer to this many bytes per second
+    #[argh(option)]
+    rate_limit: Option<u64>,
+
+    /// with --rate-limit, delay up to this many datagrams when over the limit instead of dropping them
+    #[argh(option, default = "0")]
+    rate_limit_queue: usize,
+
+    /// print the server addresses and the mosh-client command line, then exit without sending anything or starting mosh-client
+    #[argh(switch)]
+    dry_run: bool,
 }
 
 /// generate 32-byte random file to use as a key on client and server
 #[derive(FromArgs)]
 #[argh(subcommand, name = "keygen")]
 struct Keygen {
-    /// new file to generate the key to
+    /// new file to generate the key to, `-` for stdout
     #[argh(positional)]
     file: PathBuf,
+
+    /// overwrite the file if it exists. Clients and servers using the old key stop working
+    #[argh(switch)]
+    force: bool,
+
+    /// check that the existing file is a valid key instead of generating one
+    #[argh(switch)]
+    verify: bool,
+
+    /// key format, raw (default, 32 bytes) or base64 (for --key-env or --key-stdin)
+    #[argh(option, default = "KeyFormat::Raw")]
+    format: KeyFormat,
+
+    /// derive the key from passphrase read from stdin instead of generating a random one
+    #[argh(switch)]
+    from_passphrase: bool,
+
+    /// salt for deriving key from passphrase
+    #[argh(option, default = "DEFAULT_SALT.to_owned()")]
+    salt: String,
 }
 
-mod client;
-mod protocol;
-mod server;
+/// check key, mosh programs and server address without sending anything
+#[derive(FromArgs)]
+#[argh(subcommand, name = "check")]
+struct Check {
+    /// keyfile to check
+    #[argh(positional)]
+    keyfile: Option<PathBuf>,
+
+    /// server address to resolve
+    #[argh(option)]
+    addr: Option<String>,
+
+    /// check base64 key from this environment variable instead of keyfile
+    #[argh(option)]
+    key_env: Option<String>,
+
+    /// fail if keyfile is accessible by other users, instead of warning
+    #[argh(switch)]
+    strict_perms: bool,
+
+    /// limit hostname resolution to IPv4 addresses
+    #[argh(switch, short = '4')]
+    ipv4: bool,
+
+    /// limit hostname resolution to IPv6 addresses
+    #[argh(switch, short = '6')]
+    ipv6: bool,
+
+    /// check only what `serve` needs
+    #[argh(switch)]
+    server: bool,
+
+    /// check only what `connect` needs
+    #[argh(switch)]
+    client: bool,
+}
+
+/// Output format of `keygen`
+enum KeyFormat {
+    Raw,
+    Base64,
+}
+
+impl std::str::FromStr for KeyFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(KeyFormat::Raw),
+            "base64" => Ok(KeyFormat::Base64),
+            _ => anyhow::bail!("Unknown key format {}, use raw or base64", s),
+        }
+    }
+}
+
+#[cfg(unix)]
+use moshudp::server;
+use moshudp::{client, protocol};
+
+use moshudp::sockbuf::SocketBuffers;
+use moshudp::throttle::ThrottleOptions;
+use protocol::{CipherKind, NonceScheme};
+#[cfg(unix)]
+use server::{Backend, Loopback, PortRange};
+
+const DEFAULT_BUFFER_SIZE: usize = 8192;
+const DEFAULT_REPLAY_WINDOW: u64 = 3600;
+/// Used when `--salt` is not specified. Same for all deployments, so it does not
+/// protect against precomputed passphrase guessing.
+const DEFAULT_SALT: &str = "moshudp-default-salt";
+/// Largest possible UDP payload
+const MAX_BUFFER_SIZE: usize = 65536;
+
+/// Like `argh::from_env`, but with `args` that may come from `--config`
+fn parse_args(args: &[String]) -> Opts {
+    let cmd = std::path::Path::new(&args[0])
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or(&args[0]);
+    let args: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+    match Opts::from_args(&[cmd], &args) {
+        Ok(opts) => opts,
+        Err(argh::EarlyExit { output, status }) => {
+            match status {
+                Ok(()) => println!("{}", output),
+                Err(()) => eprintln!("{}\nRun {} --help for more information.", output, cmd),
+            }
+            std::process::exit(if status.is_ok() { 0 } else { 1 });
+        }
+    }
+}
 
 fn main() -> anyhow::Result<()> {
-    let opts: Opts = argh::from_env();
+    let opts = parse_args(&config::expand_args(std::env::args().collect())?);
+    let level = if opts.verbose { "debug" } else { "info" };
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level));
+    if opts.trace {
+        logger.filter_module(moshudp::trace::TARGET, log::LevelFilter::Trace);
+    }
+    if let Some(ref path) = opts.log_file {
+        let file = logfile::LogFile::open(path)?;
+        logger
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .write_style(env_logger::WriteStyle::Never)
+            .format_timestamp_millis();
+    }
+    logger.init();
     match opts.cmd {
+        #[cfg(unix)]
         Cmd::Serve(Serve {
             addr,
+            // Already merged into the other options by `config::expand_args`
+            config: _config,
+            listen,
             ipv4,
             ipv6,
             keyfile,
+            key_env,
+            key_stdin,
+            accept_key,
+            strict_perms,
+            passphrase,
+            passphrase_env,
+            salt,
+            buffer_size,
+            replay_window,
+            max_nonces,
+            nonce_store,
+            cipher,
+            nonce_scheme,
+            magic,
+            namespace,
+            compress,
+            pad_to,
+            dscp,
+            rcvbuf,
+            sndbuf,
+            bind_device,
+            mosh_server_arg,
+            command,
+            spawn_cmd,
+            connect_line_prefix,
+            connect_line_port_field,
+            connect_line_key_field,
+            spawn_timeout,
+            mosh_port_range,
+            mosh_loopback,
+            backend_addr,
+            forward_to,
+            idle_timeout,
+            handshake_timeout,
+            stats_interval,
+            metrics_addr,
+            max_sessions,
+            once,
+            mtu,
+            max_rate,
+            rate_limit,
+            rate_limit_queue,
+            systemd,
+            inetd,
+            daemon,
+            pidfile,
+            user,
+            group,
+            dry_run,
         }) => {
-            let addr = handle_addr(addr, ipv4, ipv6)?;
-            let key = std::fs::read(keyfile)?;
-            anyhow::ensure!(key.len() == 32);
-            let crypto =
-                chacha20poly1305::XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key));
-            server::Server::new(addr, crypto)?.serve();
+            check_buffer_size(buffer_size)?;
+            check_compress(compress)?;
+            check_pad_to(pad_to)?;
+            check_dscp(dscp)?;
+            anyhow::ensure!(
+                rcvbuf != Some(0) && sndbuf != Some(0),
+                "--rcvbuf and --sndbuf must be positive"
+            );
+            anyhow::ensure!(max_nonces != Some(0), "--max-nonces must be positive");
+            if let Some(rate) = max_rate {
+                anyhow::ensure!(rate > 0.0, "--max-rate must be positive");
+            }
+            anyhow::ensure!(
+                !(inetd && (systemd || daemon)),
+                "--inetd cannot be combined with --systemd or --daemon"
+            );
+            let listen = if inetd {
+                server::Listen::Inetd
+            } else if systemd || server::systemd_activated() {
+                server::Listen::Systemd
+            } else {
+                let mut addrs = Vec::new();
+                for addr in std::iter::once(addr).chain(listen) {
+                    for sa in resolve_addrs(addr, ipv4, ipv6)? {
+                        if !addrs.contains(&sa) {
+                            addrs.push(sa);
+                        }
+                    }
+                }
+                server::Listen::Bind(addrs)
+            };
+            let passphrase = get_passphrase(passphrase, passphrase_env)?;
+            let key = load_key(keyfile, key_env, key_stdin, strict_perms, passphrase, &salt)?;
+            let magic = get_magic(magic, namespace)?;
+            let crypto = protocol::Cipher::new(cipher, &key)
+                .with_magic(magic)
+                .with_nonce_scheme(nonce_scheme)?;
+            let mut extra_keys = Vec::with_capacity(accept_key.len());
+            for keyfile in accept_key {
+                let key = load_key(Some(keyfile), None, false, strict_perms, None, &salt)?;
+                extra_keys.push(
+                    protocol::Cipher::new(cipher, &key)
+                        .with_magic(magic)
+                        .with_nonce_scheme(nonce_scheme)?,
+                );
+            }
+            anyhow::ensure!(spawn_timeout > 0, "--spawn-timeout must be positive");
+            anyhow::ensure!(
+                stats_interval != Some(0),
+                "--stats-interval must be positive"
+            );
+            anyhow::ensure!(
+                handshake_timeout != Some(0),
+                "--handshake-timeout must be positive"
+            );
+            if !spawn_cmd.is_empty() && (!mosh_server_arg.is_empty() || !command.is_empty()) {
+                anyhow::bail!("--spawn-cmd cannot be combined with --mosh-server-arg or --command");
+            }
+            if !spawn_cmd.is_empty() && mosh_port_range.is_some() {
+                anyhow::bail!("--spawn-cmd cannot be combined with --mosh-port-range");
+            }
+            let mosh_options = !spawn_cmd.is_empty()
+                || !mosh_server_arg.is_empty()
+                || !command.is_empty()
+                || mosh_port_range.is_some()
+                || backend_addr.is_some();
+            anyhow::ensure!(
+                forward_to.is_none() || !mosh_options,
+                "--forward-to cannot be combined with options for starting mosh-server"
+            );
+            anyhow::ensure!(daemon || pidfile.is_none(), "--pidfile requires --daemon");
+            // Daemon changes directory to /
+            let nonce_store = match nonce_store {
+                Some(path) if daemon => Some(std::env::current_dir()?.join(path)),
+                x => x,
+            };
+            let mut mosh_server_args = mosh_server_arg;
+            if !command.is_empty() {
+                mosh_server_args.push("--".to_owned());
+                mosh_server_args.extend(command);
+            }
+            let opts = server::ServerOptions {
+                buffer_size,
+                replay_window: Duration::from_secs(replay_window),
+                max_nonces,
+                nonce_store,
+                extra_keys,
+                mosh_server_args,
+                spawn_cmd,
+                connect_line: server::ConnectLine {
+                    prefix: connect_line_prefix,
+                    port_field: connect_line_port_field,
+                    key_field: connect_line_key_field,
+                },
+                spawn_timeout: Duration::from_secs(spawn_timeout),
+                mosh_port_range,
+                loopback: mosh_loopback,
+                backend_addr,
+                forward_to,
+                idle_timeout: idle_timeout.map(Duration::from_secs),
+                handshake_timeout: handshake_timeout.map(Duration::from_secs),
+                stats_interval: stats_interval.map(Duration::from_secs),
+                metrics_addr,
+                max_sessions,
+                once,
+                compress,
+                pad_to,
+                max_rate,
+                throttle: throttle_options(rate_limit, rate_limit_queue)?,
+                mtu,
+                dscp,
+                socket_buffers: SocketBuffers { rcvbuf, sndbuf },
+                bind_device,
+            };
+            if dry_run {
+                return server::dry_run(&listen, &opts);
+            }
+            let mut server = server::Server::new(listen, crypto, opts)?;
+            if user.is_some() || group.is_some() {
+                server::drop_privileges(user.as_deref(), group.as_deref())?;
+            }
+            if daemon {
+                server::daemonize(pidfile.as_deref())?;
+            }
+            server.serve();
         }
         Cmd::Connect(Connect {
             addr,
+            // Already merged into the other options by `config::expand_args`
+            config: _config,
             ipv4,
             ipv6,
             keyfile,
+            key_env,
+            key_stdin,
+            strict_perms,
+            passphrase,
+            passphrase_env,
+            salt,
             ping,
+            status,
+            count,
+            watch,
+            interval,
+            max_loss,
+            buffer_size,
+            replay_window,
+            cipher,
+            nonce_scheme,
+            magic,
+            namespace,
+            compress,
+            pad_to,
+            dscp,
+            rcvbuf,
+            sndbuf,
+            bind_device,
+            mosh_client_arg,
+            listen,
+            emit_key,
+            connect_timeout,
+            retry_interval,
+            roam,
+            reconnect,
+            keepalive,
+            socks5,
+            bind,
+            local_port,
+            mtu,
+            rate_limit,
+            rate_limit_queue,
+            dry_run,
         }) => {
-            let addr = handle_addr(addr, ipv4, ipv6)?;
-            let key = std::fs::read(keyfile)?;
-            anyhow::ensure!(key.len() == 32);
-            let crypto =
-                chacha20poly1305::XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key));
-            client::Client::new(addr, crypto, ping)?.connect()
+            check_buffer_size(buffer_size)?;
+            check_compress(compress)?;
+            check_pad_to(pad_to)?;
+            check_dscp(dscp)?;
+            anyhow::ensure!(
+                rcvbuf != Some(0) && sndbuf != Some(0),
+                "--rcvbuf and --sndbuf must be positive"
+            );
+            anyhow::ensure!(
+                !(ping && status),
+                "--ping and --status are mutually exclusive"
+            );
+            anyhow::ensure!(
+                listen.is_none() || !(ping || status || !mosh_client_arg.is_empty()),
+                "--listen cannot be combined with --ping, --status or --mosh-client-arg"
+            );
+            anyhow::ensure!(
+                !emit_key || !(ping || status || !mosh_client_arg.is_empty()),
+                "--emit-key cannot be combined with --ping, --status or --mosh-client-arg"
+            );
+            anyhow::ensure!(keepalive != Some(0), "--keepalive must be positive");
+            anyhow::ensure!(!watch || ping, "--watch requires --ping");
+            anyhow::ensure!(
+                !(watch && count.is_some()),
+                "--watch and --count are mutually exclusive"
+            );
+            anyhow::ensure!(
+                !(reconnect && (ping || status)),
+                "--reconnect cannot be combined with --ping or --status"
+            );
+            let roam: Option<client::Resolver> = if roam {
+                let addr = addr.clone();
+                Some(Box::new(move || resolve_addrs(addr.clone(), ipv4, ipv6)))
+            } else {
+                None
+            };
+            let addrs = resolve_addrs(addr, ipv4, ipv6)?;
+            let socks5 = match socks5 {
+                Some(proxy) => Some(resolve_addrs(proxy, false, false)?[0]),
+                None => None,
+            };
+            let passphrase = get_passphrase(passphrase, passphrase_env)?;
+            let key = load_key(keyfile, key_env, key_stdin, strict_perms, passphrase, &salt)?;
+            let crypto = protocol::Cipher::new(cipher, &key)
+                .with_magic(get_magic(magic, namespace)?)
+                .with_nonce_scheme(nonce_scheme)?;
+            let opts = client::ClientOptions {
+                ping_mode: ping,
+                status_mode: status,
+                buffer_size,
+                replay_window: Duration::from_secs(replay_window),
+                mosh_client_args: mosh_client_arg,
+                retry_interval: Duration::from_millis(retry_interval),
+                connect_timeout: Duration::from_secs(connect_timeout),
+                ping_count: count,
+                ping_interval: Duration::from_millis(interval),
+                max_loss,
+                watch,
+                compress,
+                pad_to,
+                roam,
+                keepalive: keepalive.map(Duration::from_secs),
+                socks5,
+                throttle: throttle_options(rate_limit, rate_limit_queue)?,
+                bind,
+                local_port,
+                mtu,
+                dscp,
+                socket_buffers: SocketBuffers { rcvbuf, sndbuf },
+                bind_device,
+                listen,
+                emit_key,
+                reconnect,
+            };
+            if dry_run {
+                return client::dry_run(addrs, &opts);
+            }
+            client::Client::new(addrs, crypto, opts)?.connect()
         }
-        Cmd::Keygen(Keygen { file }) => {
+        Cmd::Check(Check {
+            addr,
+            keyfile,
+            key_env,
+            strict_perms,
+            ipv4,
+            ipv6,
+            server,
+            client,
+        }) => {
+            let mut failed = 0;
+            let mut report = |what: &str, result: anyhow::Result<String>| match result {
+                Ok(x) => println!("ok    {}: {}", what, x),
+                Err(e) => {
+                    println!("FAIL  {}: {}", what, e);
+                    failed += 1;
+                }
+            };
+            let (server, client) = if server || client {
+                (server, client)
+            } else {
+                (true, true)
+            };
+            if keyfile.is_some() || key_env.is_some() {
+                let key = load_key(keyfile, key_env, false, strict_perms, None, DEFAULT_SALT);
+                report("key", key.map(|_| "32 bytes".to_owned()));
+            }
+            if server {
+                #[cfg(unix)]
+                report(
+                    "mosh-server",
+                    server::find_mosh_server().map(|x| x.display().to_string()),
+                );
+                #[cfg(not(unix))]
+                report(
+                    "serve",
+                    Err(anyhow::anyhow!("not available on this platform")),
+                );
+            }
+            if client {
+                report(
+                    "mosh-client",
+                    client::find_mosh_client().map(|x| x.display().to_string()),
+                );
+            }
+            if let Some(addr) = addr {
+                let addrs = resolve_addrs(addr, ipv4, ipv6).map(|addrs| {
+                    let addrs: Vec<String> = addrs.iter().map(|x| x.to_string()).collect();
+                    addrs.join(", ")
+                });
+                report("address", addrs);
+            }
+            anyhow::ensure!(failed == 0, "{} checks failed", failed);
+        }
+        Cmd::Keygen(Keygen {
+            file,
+            force,
+            verify,
+            format,
+            from_passphrase,
+            salt,
+        }) => {
+            if verify {
+                anyhow::ensure!(
+                    !(force || from_passphrase),
+                    "--verify cannot be combined with --force or --from-passphrase"
+                );
+                return verify_keyfile(file);
+            }
             let mut buf = [0u8; 32];
-            getrandom::getrandom(&mut buf[..])?;
-            use std::os::unix::fs::OpenOptionsExt;
-            let mut f = OpenOptions::new().mode(0o600).write(true).create(true).open(file)?;
-            f.write_all(&buf)?;
+            if from_passphrase {
+                let mut passphrase = String::new();
+                std::io::stdin().read_line(&mut passphrase)?;
+                let passphrase = passphrase.trim_end_matches(&['\r', '\n'][..]);
+                buf.copy_from_slice(&derive_key(passphrase, &salt)?);
+            } else {
+                getrandom::getrandom(&mut buf[..])?;
+            }
+            let data = match format {
+                KeyFormat::Raw => buf.to_vec(),
+                KeyFormat::Base64 => {
+                    let encoded = base64::encode(buf);
+                    anyhow::ensure!(
+                        decode_key(&encoded)? == buf,
+                        "base64 key does not round-trip"
+                    );
+                    format!("{}\n", encoded).into_bytes()
+                }
+            };
+            if file.as_os_str() == "-" {
+                std::io::stdout().write_all(&data)?;
+            } else {
+                let mut oo = OpenOptions::new();
+                if force {
+                    oo.write(true).create(true).truncate(true);
+                } else {
+                    oo.write(true).create_new(true);
+                }
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    oo.mode(0o600);
+                }
+                let mut f = oo.open(&file).map_err(|e| match e.kind() {
+                    std::io::ErrorKind::AlreadyExists => anyhow::anyhow!(
+                        "{} already exists, use --force to overwrite it or --verify to check it",
+                        file.display()
+                    ),
+                    _ => anyhow::anyhow!("Cannot create {}: {}", file.display(), e),
+                })?;
+                f.write_all(&data)?;
+            }
         }
     }
     Ok(())
 }
 
-fn handle_addr(addr: String, ipv4: bool, ipv6: bool) -> Result<SocketAddr, anyhow::Error> {
-    let mut addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+/// `keygen --verify`: key must load like for `serve` and `connect` and not be trivially weak
+fn verify_keyfile(file: PathBuf) -> anyhow::Result<()> {
+    let format = match std::fs::metadata(&file)?.len() {
+        32 => "raw",
+        _ => "base64",
+    };
+    let key = load_key(Some(file.clone()), None, false, false, None, DEFAULT_SALT)
+        .map_err(|e| anyhow::anyhow!("{} is not a valid key: {}", file.display(), e))?;
+    anyhow::ensure!(
+        key.iter().any(|&b| b != key[0]),
+        "{} has the same value in all 32 bytes, it is not a random key",
+        file.display()
+    );
+    println!("{}: valid {} key", file.display(), format);
+    Ok(())
+}
+
+fn resolve_addrs(addr: String, ipv4: bool, ipv6: bool) -> Result<Vec<SocketAddr>, anyhow::Error> {
+    let mut addrs = moshudp::addr::resolve(&addr)?;
     addrs.retain(|a| match a {
         SocketAddr::V4(_) => !ipv6,
         SocketAddr::V6(_) => !ipv4,
@@ -129,8 +1038,176 @@ fn handle_addr(addr: String, ipv4: bool, ipv6: bool) -> Result<SocketAddr, anyho
     if addrs.is_empty() {
         anyhow::bail!("No usable socket addresses obtained");
     }
-    if addrs.len() > 1 {
-        anyhow::bail!("Listening or connecting to multiple UDP socket addresses is not supported");
+    Ok(addrs)
+}
+
+fn get_passphrase(
+    passphrase: Option<String>,
+    passphrase_env: Option<String>,
+) -> anyhow::Result<Option<String>> {
+    match (passphrase, passphrase_env) {
+        (None, None) => Ok(None),
+        (Some(p), None) => Ok(Some(p)),
+        (None, Some(var)) => {
+            Ok(Some(std::env::var(&var).map_err(|e| {
+                anyhow::anyhow!("Cannot read passphrase from ${}: {}", var, e)
+            })?))
+        }
+        (Some(_), Some(_)) => {
+            anyhow::bail!("Only one of --passphrase or --passphrase-env may be specified")
+        }
+    }
+}
+
+fn load_key(
+    keyfile: Option<PathBuf>,
+    key_env: Option<String>,
+    key_stdin: bool,
+    strict_perms: bool,
+    passphrase: Option<String>,
+    salt: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let key = match (keyfile, key_env, key_stdin, passphrase) {
+        (Some(keyfile), None, false, None) => {
+            check_keyfile_permissions(&keyfile, strict_perms)?;
+            let data = std::fs::read(keyfile)?;
+            if data.len() == 32 {
+                data
+            } else {
+                // written by `keygen --format base64`
+                decode_key(&String::from_utf8_lossy(&data))?
+            }
+        }
+        (None, Some(var), false, None) => {
+            let val = std::env::var(&var)
+                .map_err(|e| anyhow::anyhow!("Cannot read key from ${}: {}", var, e))?;
+            decode_key(&val)?
+        }
+        (None, None, true, None) => {
+            let mut val = String::new();
+            std::io::stdin().read_line(&mut val)?;
+            decode_key(&val)?
+        }
+        (None, None, false, Some(passphrase)) => derive_key(&passphrase, salt)?,
+        (None, None, false, None) => {
+            anyhow::bail!("Specify keyfile, --key-env, --key-stdin or passphrase")
+        }
+        _ => anyhow::bail!(
+            "Only one of keyfile, --key-env, --key-stdin or passphrase may be specified"
+        ),
+    };
+    anyhow::ensure!(
+        key.len() == 32,
+        "Key must be exactly 32 bytes, got {}",
+        key.len()
+    );
+    Ok(key)
+}
+
+/// Like ssh with private keys, complain if other users may read the keyfile
+#[cfg(unix)]
+fn check_keyfile_permissions(keyfile: &std::path::Path, strict: bool) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(keyfile)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        if strict {
+            anyhow::bail!(
+                "Keyfile {} is accessible by other users (mode {:o})",
+                keyfile.display(),
+                mode & 0o777
+            );
+        }
+        log::warn!(
+            "Keyfile {} is accessible by other users (mode {:o}), consider chmod 600",
+            keyfile.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_keyfile_permissions(_keyfile: &std::path::Path, _strict: bool) -> anyhow::Result<()> {
+    Ok(())
+}
+
+fn decode_key(val: &str) -> anyhow::Result<Vec<u8>> {
+    base64::decode(val.trim()).map_err(|e| anyhow::anyhow!("Key is not valid base64: {}", e))
+}
+
+/// Argon2id with default parameters, so that `keygen --from-passphrase` and
+/// `--passphrase` produce the same key
+fn derive_key(passphrase: &str, salt: &str) -> anyhow::Result<Vec<u8>> {
+    let mut key = vec![0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive key from passphrase: {}", e))?;
+    Ok(key)
+}
+
+fn get_magic(magic: Option<String>, namespace: Option<String>) -> anyhow::Result<u32> {
+    match (magic, namespace) {
+        (Some(_), Some(_)) => anyhow::bail!("--magic and --namespace are mutually exclusive"),
+        (Some(x), None) => u32::from_str_radix(x.trim_start_matches("0x"), 16)
+            .map_err(|e| anyhow::anyhow!("Invalid --magic {}: {}", x, e)),
+        (None, Some(x)) => Ok(protocol::namespace_magic(&x)),
+        (None, None) => Ok(protocol::MAGIC),
+    }
+}
+
+fn check_buffer_size(buffer_size: usize) -> anyhow::Result<()> {
+    if buffer_size < protocol::MIN_DATAGRAM_SIZE {
+        anyhow::bail!(
+            "Buffer size {} is too small, minimum is {}",
+            buffer_size,
+            protocol::MIN_DATAGRAM_SIZE
+        );
+    }
+    if buffer_size > MAX_BUFFER_SIZE {
+        anyhow::bail!(
+            "Buffer size {} is too large, maximum is {}",
+            buffer_size,
+            MAX_BUFFER_SIZE
+        );
+    }
+    Ok(())
+}
+
+fn check_compress(compress: bool) -> anyhow::Result<()> {
+    if compress && !cfg!(feature = "compression") {
+        anyhow::bail!("--compress requires moshudp to be built with `compression` feature");
+    }
+    Ok(())
+}
+
+fn check_dscp(dscp: Option<u8>) -> anyhow::Result<()> {
+    if let Some(dscp) = dscp {
+        anyhow::ensure!(dscp < 64, "--dscp must be between 0 and 63, got {}", dscp);
+    }
+    Ok(())
+}
+
+fn check_pad_to(pad_to: Option<usize>) -> anyhow::Result<()> {
+    if let Some(n) = pad_to {
+        anyhow::ensure!(
+            n > 0 && n <= protocol::MAX_MESSAGE_SIZE,
+            "--pad-to must be from 1 to {}",
+            protocol::MAX_MESSAGE_SIZE
+        );
+    }
+    Ok(())
+}
+
+fn throttle_options(
+    rate_limit: Option<u64>,
+    rate_limit_queue: usize,
+) -> anyhow::Result<Option<ThrottleOptions>> {
+    match rate_limit {
+        Some(0) => anyhow::bail!("--rate-limit must be positive"),
+        Some(rate) => Ok(Some(ThrottleOptions {
+            rate,
+            queue: rate_limit_queue,
+        })),
+        None => Ok(None),
     }
-    Ok(addrs[0])
 }
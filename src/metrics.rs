@@ -0,0 +1,156 @@
+//! `--metrics-addr`: Prometheus text format endpoint with server counters.
+//!
+//! Scrapes are answered one at a time on a background thread from a snapshot that the serve
+//! loop publishes, so a slow scraper cannot stall forwarding.
+
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::protocol::Counters;
+
+/// Scrapers that do not send their request in this time are disconnected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request head read, the rest is ignored
+const MAX_REQUEST_SIZE: usize = 4096;
+
+#[derive(Default, Clone, Copy)]
+struct Snapshot {
+    sessions: usize,
+    counters: Counters,
+    nonces: usize,
+    nonces_evicted: u64,
+}
+
+pub(crate) struct Metrics {
+    snapshot: Arc<Mutex<Snapshot>>,
+    /// Taken by [`Metrics::start`]
+    listener: Option<TcpListener>,
+}
+
+impl Metrics {
+    pub fn bind(addr: SocketAddr) -> anyhow::Result<Metrics> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| anyhow::anyhow!("Failed to bind --metrics-addr {}: {}", addr, e))?;
+        Ok(Metrics {
+            snapshot: Default::default(),
+            listener: Some(listener),
+        })
+    }
+
+    /// Start answering scrapes. Called from the serve loop, as the thread would not
+    /// survive `--daemon` fork.
+    pub fn start(&mut self) {
+        let listener = match self.listener.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let snapshot = self.snapshot.clone();
+        let ret = std::thread::Builder::new()
+            .name("metrics".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = handle(stream, &snapshot) {
+                                debug!("Metrics request failed: {}", e);
+                            }
+                        }
+                        Err(e) => warn!("Failed to accept metrics connection: {}", e),
+                    }
+                }
+            });
+        if let Err(e) = ret {
+            warn!("Cannot start metrics thread: {}", e);
+        }
+    }
+
+    pub fn update(&self, sessions: usize, counters: Counters, nonces: usize, nonces_evicted: u64) {
+        if let Ok(mut x) = self.snapshot.lock() {
+            *x = Snapshot {
+                sessions,
+                counters,
+                nonces,
+                nonces_evicted,
+            };
+        }
+    }
+}
+
+fn handle(mut stream: TcpStream, snapshot: &Mutex<Snapshot>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut buf = vec![0u8; MAX_REQUEST_SIZE];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let request_line = buf[..len].split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut words = request_line.split(|&b| b == b' ');
+    let (method, path) = (words.next(), words.next());
+    let (status, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            let x = snapshot.lock().map(|x| *x).unwrap_or_default();
+            ("200 OK", render(&x))
+        }
+        (Some(b"GET"), _) => ("404 Not Found", "Try /metrics\n".to_owned()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn render(x: &Snapshot) -> String {
+    let t = &x.counters.traffic;
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "# HELP moshudp_sessions Active sessions.\n\
+         # TYPE moshudp_sessions gauge\n\
+         moshudp_sessions {}\n\
+         # HELP moshudp_packets_total Datagrams exchanged with clients.\n\
+         # TYPE moshudp_packets_total counter\n\
+         moshudp_packets_total{{direction=\"in\"}} {}\n\
+         moshudp_packets_total{{direction=\"out\"}} {}\n\
+         # HELP moshudp_bytes_total Bytes of datagrams exchanged with clients.\n\
+         # TYPE moshudp_bytes_total counter\n\
+         moshudp_bytes_total{{direction=\"in\"}} {}\n\
+         moshudp_bytes_total{{direction=\"out\"}} {}\n\
+         # HELP moshudp_decrypt_failures_total Datagrams with moshudp magic that could not be decrypted or parsed.\n\
+         # TYPE moshudp_decrypt_failures_total counter\n\
+         moshudp_decrypt_failures_total {}\n\
+         # HELP moshudp_replay_hits_total Datagrams rejected by replay protection.\n\
+         # TYPE moshudp_replay_hits_total counter\n\
+         moshudp_replay_hits_total {}\n\
+         # HELP moshudp_replay_nonces Nonces remembered for replay protection.\n\
+         # TYPE moshudp_replay_nonces gauge\n\
+         moshudp_replay_nonces {}\n\
+         # HELP moshudp_replay_nonces_evicted_total Nonces forgotten before the end of the replay window because of --max-nonces.\n\
+         # TYPE moshudp_replay_nonces_evicted_total counter\n\
+         moshudp_replay_nonces_evicted_total {}\n",
+        x.sessions,
+        t.packets_in,
+        t.packets_out,
+        t.bytes_in,
+        t.bytes_out,
+        x.counters.decrypt_failures,
+        x.counters.replay_hits,
+        x.nonces,
+        x.nonces_evicted,
+    );
+    out
+}
@@ -0,0 +1,43 @@
+//! `--mtu` check of datagrams sent to the network peer.
+//!
+//! mosh traffic is forwarded as is, without moshudp's own framing, so only SOCKS5 encapsulation
+//! adds to mosh's datagram size. The check just reports datagrams that are likely to be
+//! fragmented, it does not change what is sent.
+
+use std::{cell::Cell, net::SocketAddr};
+
+use log::warn;
+
+/// Size of IP and UDP headers in front of a datagram sent to `addr`
+pub(crate) fn headers_size(addr: SocketAddr) -> usize {
+    match addr {
+        SocketAddr::V4(_) => 20 + 8,
+        SocketAddr::V6(_) => 40 + 8,
+    }
+}
+
+/// Warns once about the first datagram that does not fit into `mtu`
+pub(crate) struct MtuCheck {
+    mtu: usize,
+    warned: Cell<bool>,
+}
+
+impl MtuCheck {
+    pub(crate) fn new(mtu: usize) -> MtuCheck {
+        MtuCheck {
+            mtu,
+            warned: Cell::new(false),
+        }
+    }
+
+    /// `len` is UDP payload size
+    pub(crate) fn check(&self, len: usize, dest: SocketAddr) {
+        let size = len + headers_size(dest);
+        if size > self.mtu && !self.warned.replace(true) {
+            warn!(
+                "Datagram of {} bytes to {} exceeds MTU of {} bytes and may be fragmented or dropped. Further occurrences are not reported",
+                size, dest, self.mtu
+            );
+        }
+    }
+}
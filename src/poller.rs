@@ -0,0 +1,257 @@
+//! Waiting for readability of several sockets at once, `poll(2)` on Unix and `WSAPoll` on Windows.
+//! With `async` feature Unix event loops wait in a current-thread tokio runtime instead.
+
+use std::os::raw::c_int;
+
+/// Socket a [`Poller`] can wait on: UDP sockets, and Unix datagram sockets of
+/// `serve --forward-to unix:PATH`
+#[cfg(unix)]
+pub trait Pollable: std::os::unix::io::AsRawFd {}
+#[cfg(unix)]
+impl<T: std::os::unix::io::AsRawFd> Pollable for T {}
+#[cfg(windows)]
+pub trait Pollable: std::os::windows::io::AsRawSocket {}
+#[cfg(windows)]
+impl<T: std::os::windows::io::AsRawSocket> Pollable for T {}
+
+/// Waits until some of the sockets become readable
+pub trait Poller {
+    /// Sockets for the following `poll` calls, replacing earlier ones. Must be called again
+    /// before `poll` once any of them is closed, as its fd may be reused.
+    fn set_sockets(&mut self, sockets: &[&dyn Pollable]) -> std::io::Result<()>;
+
+    /// `timeout` is in milliseconds, -1 means infinite, like in `poll(2)`.
+    /// Returns number of ready sockets, 0 on timeout.
+    /// Fails with [`std::io::ErrorKind::Interrupted`] if a signal arrives while waiting.
+    fn poll(&mut self, timeout: i32) -> std::io::Result<usize>;
+
+    /// Whether socket with this index in `set_sockets` was readable in the last `poll` call
+    fn is_readable(&self, index: usize) -> bool;
+
+    /// Make `poll` fail with `Interrupted` when one of `signals` arrives. Plain `poll(2)`
+    /// does it by itself for signals that have a handler.
+    fn interrupt_on(&mut self, _signals: &[c_int]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(feature = "async")))]
+pub type PlatformPoller = unix::UnixPoller;
+#[cfg(all(unix, feature = "async"))]
+pub type PlatformPoller = tokio_poller::TokioPoller;
+#[cfg(windows)]
+pub type PlatformPoller = windows::WindowsPoller;
+
+#[cfg(all(unix, not(feature = "async")))]
+mod unix {
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    use super::Pollable;
+
+    #[derive(Default)]
+    pub struct UnixPoller {
+        polls: Vec<PollFd>,
+    }
+
+    impl super::Poller for UnixPoller {
+        fn set_sockets(&mut self, sockets: &[&dyn Pollable]) -> std::io::Result<()> {
+            self.polls.clear();
+            self.polls.extend(
+                sockets
+                    .iter()
+                    .map(|s| PollFd::new(s.as_raw_fd(), PollFlags::POLLIN)),
+            );
+            Ok(())
+        }
+
+        fn poll(&mut self, timeout: i32) -> std::io::Result<usize> {
+            // `poll` overwrites `revents` of reused entries
+            Ok(poll(&mut self.polls[..], timeout)? as usize)
+        }
+
+        fn is_readable(&self, index: usize) -> bool {
+            let revents = self.polls.get(index).and_then(|p| p.revents());
+            matches!(revents, Some(x) if x.contains(PollFlags::POLLIN))
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "async"))]
+mod tokio_poller {
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::{
+        future::{pending, poll_fn},
+        os::{raw::c_int, unix::prelude::RawFd},
+        task::Poll,
+        time::Duration,
+    };
+    use tokio::{
+        io::unix::AsyncFd,
+        runtime::{Builder, Runtime},
+        signal::unix::{signal, Signal, SignalKind},
+    };
+
+    use super::Pollable;
+
+    pub struct TokioPoller {
+        runtime: Runtime,
+        signals: Vec<Signal>,
+        /// Registered with the runtime by `set_sockets`
+        fds: Vec<AsyncFd<RawFd>>,
+        /// Same sockets, for finding datagrams left unread since the last `poll`
+        polls: Vec<PollFd>,
+        readable: Vec<bool>,
+    }
+
+    impl Default for TokioPoller {
+        fn default() -> Self {
+            let runtime = Builder::new_current_thread()
+                .enable_io()
+                .enable_time()
+                .build()
+                .expect("Failed to start tokio runtime");
+            TokioPoller {
+                runtime,
+                signals: Vec::new(),
+                fds: Vec::new(),
+                polls: Vec::new(),
+                readable: Vec::new(),
+            }
+        }
+    }
+
+    impl super::Poller for TokioPoller {
+        fn set_sockets(&mut self, sockets: &[&dyn Pollable]) -> std::io::Result<()> {
+            let _guard = self.runtime.enter();
+            // Deregistered first, a new socket may have the fd of a closed one
+            self.fds.clear();
+            for s in sockets {
+                self.fds.push(AsyncFd::new(s.as_raw_fd())?);
+            }
+            self.polls.clear();
+            self.polls.extend(
+                sockets
+                    .iter()
+                    .map(|s| PollFd::new(s.as_raw_fd(), PollFlags::POLLIN)),
+            );
+            self.readable.clear();
+            self.readable.resize(sockets.len(), false);
+            Ok(())
+        }
+
+        fn poll(&mut self, timeout: i32) -> std::io::Result<usize> {
+            let fds = &self.fds;
+            let polls = &mut self.polls;
+            let readable = &mut self.readable;
+            let signals = &mut self.signals;
+            readable.fill(false);
+            self.runtime.block_on(async move {
+                // tokio reports readiness once per arrival, so it is cleared here and
+                // datagrams that were there before are found by a non-blocking poll(2)
+                poll_fn(|cx| {
+                    for fd in fds {
+                        if let Poll::Ready(Ok(mut guard)) = fd.poll_read_ready(cx) {
+                            guard.clear_ready();
+                        }
+                    }
+                    Poll::Ready(())
+                })
+                .await;
+                let n = poll(&mut polls[..], 0)?;
+                if n > 0 {
+                    for (p, r) in polls.iter().zip(readable.iter_mut()) {
+                        *r = matches!(p.revents(), Some(x) if x.contains(PollFlags::POLLIN));
+                    }
+                    return Ok(n as usize);
+                }
+                let ready = poll_fn(|cx| {
+                    let mut n = 0;
+                    for (fd, r) in fds.iter().zip(readable.iter_mut()) {
+                        match fd.poll_read_ready(cx) {
+                            Poll::Ready(Ok(_)) => {
+                                *r = true;
+                                n += 1;
+                            }
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => (),
+                        }
+                    }
+                    if n > 0 {
+                        Poll::Ready(Ok(n))
+                    } else {
+                        Poll::Pending
+                    }
+                });
+                let signalled = poll_fn(|cx| {
+                    if signals.iter_mut().any(|s| s.poll_recv(cx).is_ready()) {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                });
+                let timer = async {
+                    match u64::try_from(timeout) {
+                        Ok(ms) => tokio::time::sleep(Duration::from_millis(ms)).await,
+                        Err(_) => pending().await,
+                    }
+                };
+                tokio::select! {
+                    biased;
+                    _ = signalled => Err(std::io::ErrorKind::Interrupted.into()),
+                    n = ready => n,
+                    _ = timer => Ok(0),
+                }
+            })
+        }
+
+        fn is_readable(&self, index: usize) -> bool {
+            self.readable.get(index).copied().unwrap_or(false)
+        }
+
+        /// tokio restarts interrupted waits, so signals are watched explicitly
+        fn interrupt_on(&mut self, signals: &[c_int]) -> std::io::Result<()> {
+            let _guard = self.runtime.enter();
+            for &x in signals {
+                self.signals.push(signal(SignalKind::from_raw(x))?);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::Pollable;
+    use windows_sys::Win32::Networking::WinSock::{WSAPoll, POLLRDNORM, SOCKET, WSAPOLLFD};
+
+    #[derive(Default)]
+    pub struct WindowsPoller {
+        polls: Vec<WSAPOLLFD>,
+    }
+
+    impl super::Poller for WindowsPoller {
+        fn set_sockets(&mut self, sockets: &[&dyn Pollable]) -> std::io::Result<()> {
+            self.polls.clear();
+            self.polls.extend(sockets.iter().map(|s| WSAPOLLFD {
+                fd: s.as_raw_socket() as SOCKET,
+                events: POLLRDNORM as i16,
+                revents: 0,
+            }));
+            Ok(())
+        }
+
+        fn poll(&mut self, timeout: i32) -> std::io::Result<usize> {
+            // `WSAPoll` overwrites `revents` of reused entries
+            // Safety: pointer and length come from a live Vec
+            let ret = unsafe { WSAPoll(self.polls.as_mut_ptr(), self.polls.len() as u32, timeout) };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(ret as usize)
+        }
+
+        fn is_readable(&self, index: usize) -> bool {
+            matches!(self.polls.get(index), Some(p) if p.revents & POLLRDNORM as i16 != 0)
+        }
+    }
+}
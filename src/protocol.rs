@@ -1,70 +1,1031 @@
-use fxhash::FxHashSet;
+use std::{
+    collections::VecDeque,
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{aead::NewAead, XChaCha20Poly1305};
+use fxhash::FxHashMap;
+use generic_array::GenericArray;
 use serde::{Deserialize, Serialize};
 
-pub const MAGIC: u32 = 0x5564_4d6f;
+/// Changed from 0x5564_4d6f when nonce started to carry cipher discriminator.
+/// Default of [`Cipher::magic`].
+pub const MAGIC: u32 = 0x5564_4d32;
+
+/// Bumped on every incompatible change of `Datagram` or `Message`
+pub const PROTOCOL_VERSION: u8 = 7;
 
+/// Nonce as used for replay protection. Shorter nonces are padded with zeroes.
 pub type Nonce = [u8; 24];
 
+/// Size of the smallest valid encrypted datagram: magic, version, cipher discriminant,
+/// shortest nonce, compression flag, data length, AEAD tag, padding length prefix and enum discriminant
+/// of the message.
+pub const MIN_DATAGRAM_SIZE: usize = 4 + 1 + 4 + 12 + 1 + 8 + 16 + 2 + 4;
+
+/// Messages with shorter bincode are never compressed
+pub const COMPRESSION_THRESHOLD: usize = 64;
+
+/// Maximum size of bincode of a [`Message`], after decryption and decompression.
+/// Senders refuse to encrypt larger messages, receivers reject them as [`DecryptError::TooLarge`].
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Limit for deserializing the outer [`Datagram`], the largest UDP payload
+const MAX_DATAGRAM_SIZE: u64 = 65536;
+
+/// Outer layer of every moshudp control packet, serialized with bincode
 #[derive(Serialize, Deserialize)]
 pub struct Datagram {
+    pub magic: u32,
+    pub version: u8,
+    pub nonce: DatagramNonce,
+    /// Whether `data` decrypts to zstd-compressed bincode
+    pub compressed: bool,
+    /// Encrypted bincode of [`Message`], header fields above are authenticated as associated data
+    pub data: Vec<u8>,
+}
+
+/// Nonce together with the cipher it is for. Both peers must use the same cipher.
+#[derive(Serialize, Deserialize)]
+pub enum DatagramNonce {
+    XChaCha20Poly1305([u8; 24]),
+    Aes256Gcm([u8; 12]),
+}
+
+impl DatagramNonce {
+    fn replay_key(&self) -> Nonce {
+        match self {
+            DatagramNonce::XChaCha20Poly1305(n) => *n,
+            DatagramNonce::Aes256Gcm(n) => {
+                let mut key = [0u8; 24];
+                key[..12].copy_from_slice(&n[..]);
+                key
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CipherKind {
+    XChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl FromStr for CipherKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xchacha20poly1305" => Ok(CipherKind::XChaCha20Poly1305),
+            "aes256gcm" => Ok(CipherKind::Aes256Gcm),
+            _ => anyhow::bail!("Unknown cipher {}, use xchacha20poly1305 or aes256gcm", s),
+        }
+    }
+}
+
+/// How [`encrypt`] makes nonces. Receivers accept either.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NonceScheme {
+    /// All bytes random
+    Random,
+    /// Random per-process prefix, then big-endian counter, then random bytes: 8+8+8 bytes
+    /// for XChaCha20-Poly1305, 4+8+0 for AES-256-GCM. Never repeats within a process.
+    Counter,
+}
+
+impl FromStr for NonceScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(NonceScheme::Random),
+            "counter" => Ok(NonceScheme::Counter),
+            _ => anyhow::bail!("Unknown nonce scheme {}, use random or counter", s),
+        }
+    }
+}
+
+/// State of [`NonceScheme::Counter`], shared by clones of a [`Cipher`]
+struct NonceCounter {
+    prefix: [u8; 8],
+    next: AtomicU64,
+}
+
+impl NonceCounter {
+    fn new() -> anyhow::Result<NonceCounter> {
+        let mut prefix = [0u8; 8];
+        getrandom::getrandom(&mut prefix)?;
+        // Random start keeps short AES-GCM nonces of processes with the same prefix apart
+        let mut start = [0u8; 8];
+        getrandom::getrandom(&mut start)?;
+        Ok(NonceCounter {
+            prefix,
+            next: AtomicU64::new(u64::from_be_bytes(start)),
+        })
+    }
+
+    /// Overwrites the start of random `nonce` with prefix and counter
+    fn stamp(&self, nonce: &mut [u8]) {
+        let prefix_len = if nonce.len() >= 24 { 8 } else { 4 };
+        let counter = self.next.fetch_add(1, Ordering::Relaxed);
+        nonce[..prefix_len].copy_from_slice(&self.prefix[..prefix_len]);
+        nonce[prefix_len..prefix_len + 8].copy_from_slice(&counter.to_be_bytes());
+    }
+}
+
+#[derive(Clone)]
+enum AeadCipher {
+    XChaCha20Poly1305(XChaCha20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+/// Key and parameters shared by both peers
+#[derive(Clone)]
+pub struct Cipher {
+    aead: AeadCipher,
     magic: u32,
-    nonce: Nonce,
-    data: Vec<u8>,
+    /// `None` for [`NonceScheme::Random`]
+    nonce_counter: Option<Arc<NonceCounter>>,
+}
+
+impl Cipher {
+    /// `key` must be 32 bytes long
+    pub fn new(kind: CipherKind, key: &[u8]) -> Cipher {
+        let aead = match kind {
+            CipherKind::XChaCha20Poly1305 => {
+                AeadCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(GenericArray::from_slice(key)))
+            }
+            CipherKind::Aes256Gcm => {
+                AeadCipher::Aes256Gcm(Box::new(Aes256Gcm::new(GenericArray::from_slice(key))))
+            }
+        };
+        Cipher {
+            aead,
+            magic: MAGIC,
+            nonce_counter: None,
+        }
+    }
+
+    /// Use another magic number to tell apart independent deployments. Datagrams with other
+    /// magic are rejected as [`DecryptError::BadMagic`] before decryption.
+    pub fn with_magic(self, magic: u32) -> Cipher {
+        Cipher { magic, ..self }
+    }
+
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    /// Make nonces of outgoing datagrams according to `scheme` instead of fully random
+    pub fn with_nonce_scheme(self, scheme: NonceScheme) -> anyhow::Result<Cipher> {
+        let nonce_counter = match scheme {
+            NonceScheme::Random => None,
+            NonceScheme::Counter => Some(Arc::new(NonceCounter::new()?)),
+        };
+        Ok(Cipher {
+            nonce_counter,
+            ..self
+        })
+    }
+
+    /// Fills `nonce` for the next outgoing datagram
+    fn make_nonce(&self, nonce: &mut [u8]) -> anyhow::Result<()> {
+        getrandom::getrandom(nonce)?;
+        if let Some(ref counter) = self.nonce_counter {
+            counter.stamp(nonce);
+        }
+        Ok(())
+    }
+}
+
+/// Magic number for `--namespace`: 32-bit FNV-1a hash of its name
+pub fn namespace_magic(namespace: &str) -> u32 {
+    namespace
+        .bytes()
+        .fold(0x811c_9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193))
 }
 
-#[derive(Serialize, Deserialize,Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum Message {
-    Ping,
-    Pong,
-    StartServer { sessid: u64 },
+    /// `seq` is echoed back in `Pong` to match replies to requests
+    Ping { seq: u64 },
+    Pong { seq: u64 },
+    /// `cookie` is a copy of `Cookie` token, server does not start mosh-server without it
+    StartServer {
+        sessid: u64,
+        cookie: Option<CookieToken>,
+    },
     ServerStarted { key: String },
     Failed { msg: String },
     /// Sent by server to client when client's external address change, to confirm the change
-    /// Client replies with the same message back
-    UpdateAddress,
+    /// Client replies with the same message back, filling in its session id
+    UpdateAddress { sessid: u64 },
+    /// Sent by server in reply to `StartServer` without valid cookie, to check that client
+    /// receives replies at its source address before spawning a process for it
+    Cookie { token: CookieToken },
+    /// Request for [`Message::StatusReply`], for diagnostics
+    Status,
+    StatusReply {
+        active_sessions: u64,
+        uptime_secs: u64,
+        /// moshudp version of the server
+        version: String,
+        counters: Counters,
+    },
+    /// Sent by client when mosh-client exits, so server stops mosh-server right away.
+    /// Not acknowledged and may be lost.
+    Bye { sessid: u64 },
+}
+
+/// Datagrams and bytes exchanged with clients
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Traffic {
+    pub fn received(&mut self, len: usize) {
+        self.packets_in += 1;
+        self.bytes_in += len as u64;
+    }
+
+    pub fn sent(&mut self, len: usize) {
+        self.packets_out += 1;
+        self.bytes_out += len as u64;
+    }
+}
+
+impl std::fmt::Display for Traffic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "in {} packets {} bytes, out {} packets {} bytes",
+            self.packets_in, self.bytes_in, self.packets_out, self.bytes_out
+        )
+    }
+}
+
+/// Server-wide counters since startup
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    pub traffic: Traffic,
+    /// Datagrams with moshudp magic that could not be decrypted or parsed
+    pub decrypt_failures: u64,
+    /// Datagrams rejected by replay protection
+    pub replay_hits: u64,
+}
+
+impl std::fmt::Display for Counters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, {} decrypt failures, {} replays",
+            self.traffic, self.decrypt_failures, self.replay_hits
+        )
+    }
+}
+
+/// Like derived, but without mosh session key
+impl std::fmt::Debug for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Message::Ping { seq } => f.debug_struct("Ping").field("seq", seq).finish(),
+            Message::Pong { seq } => f.debug_struct("Pong").field("seq", seq).finish(),
+            Message::StartServer { sessid, cookie } => f
+                .debug_struct("StartServer")
+                .field("sessid", sessid)
+                .field("cookie", cookie)
+                .finish(),
+            Message::ServerStarted { .. } => f
+                .debug_struct("ServerStarted")
+                .field("key", &format_args!("<redacted>"))
+                .finish(),
+            Message::Failed { msg } => f.debug_struct("Failed").field("msg", msg).finish(),
+            Message::UpdateAddress { sessid } => f
+                .debug_struct("UpdateAddress")
+                .field("sessid", sessid)
+                .finish(),
+            Message::Cookie { token } => f.debug_struct("Cookie").field("token", token).finish(),
+            Message::Status => f.write_str("Status"),
+            Message::StatusReply {
+                active_sessions,
+                uptime_secs,
+                version,
+                counters,
+            } => f
+                .debug_struct("StatusReply")
+                .field("active_sessions", active_sessions)
+                .field("uptime_secs", uptime_secs)
+                .field("version", version)
+                .field("counters", counters)
+                .finish(),
+            Message::Bye { sessid } => f.debug_struct("Bye").field("sessid", sessid).finish(),
+        }
+    }
+}
+
+impl Message {
+    /// `Failed` message with `msg` truncated to fit into [`MAX_MESSAGE_SIZE`]
+    pub fn failed(msg: impl Into<String>) -> Message {
+        let mut msg = msg.into();
+        // Room for enum discriminant and string length
+        let max_len = MAX_MESSAGE_SIZE - 4 - 8;
+        if msg.len() > max_len {
+            let mut end = max_len;
+            while !msg.is_char_boundary(end) {
+                end -= 1;
+            }
+            msg.truncate(end);
+        }
+        Message::Failed { msg }
+    }
+}
+
+pub type CookieToken = [u8; 16];
+
+/// Validity period of cookie tokens. Tokens from the previous period are also accepted.
+const COOKIE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Issues and checks stateless cookies bound to client addresses. A token is
+/// the Poly1305 tag of empty message under a random per-process key, with
+/// client address and time period as the nonce.
+pub struct CookieJar {
+    cipher: XChaCha20Poly1305,
+    start: Instant,
+}
+
+impl CookieJar {
+    pub fn new() -> anyhow::Result<CookieJar> {
+        let mut key = [0u8; 32];
+        getrandom::getrandom(&mut key[..])?;
+        Ok(CookieJar {
+            cipher: XChaCha20Poly1305::new(GenericArray::from_slice(&key)),
+            start: Instant::now(),
+        })
+    }
+
+    fn period(&self) -> u32 {
+        (self.start.elapsed().as_secs() / COOKIE_PERIOD.as_secs()) as u32
+    }
+
+    fn token(&self, addr: SocketAddr, period: u32) -> Option<CookieToken> {
+        let ip = match addr {
+            SocketAddr::V4(a) => a.ip().to_ipv6_mapped(),
+            SocketAddr::V6(a) => *a.ip(),
+        };
+        let mut nonce = [0u8; 24];
+        nonce[..16].copy_from_slice(&ip.octets());
+        nonce[16..18].copy_from_slice(&addr.port().to_be_bytes());
+        nonce[18..22].copy_from_slice(&period.to_be_bytes());
+        let tag = self
+            .cipher
+            .encrypt(GenericArray::from_slice(&nonce), &[][..])
+            .ok()?;
+        tag.try_into().ok()
+    }
+
+    pub fn issue(&self, addr: SocketAddr) -> Option<CookieToken> {
+        self.token(addr, self.period())
+    }
+
+    pub fn check(&self, addr: SocketAddr, token: &CookieToken) -> bool {
+        let period = self.period();
+        [period, period.wrapping_sub(1)]
+            .iter()
+            .any(|p| self.token(addr, *p).as_ref() == Some(token))
+    }
+}
+
+/// Remembers nonces of recently received datagrams to reject replays.
+/// Nonces older than `window` are forgotten one by one instead of all at once.
+pub struct ReplayGuard {
+    window: Duration,
+    seen: FxHashMap<Nonce, Instant>,
+    /// Nonces in order of insertion, for expiring them
+    queue: VecDeque<(Instant, Nonce)>,
+    /// Oldest nonces are forgotten before the end of `window` to stay within this many
+    max_len: Option<usize>,
+    /// Number of nonces forgotten early because of `max_len`
+    evicted: u64,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration) -> ReplayGuard {
+        ReplayGuard {
+            window,
+            seen: FxHashMap::default(),
+            queue: VecDeque::new(),
+            max_len: None,
+            evicted: 0,
+        }
+    }
+
+    /// Bound memory use regardless of traffic rate. Datagrams whose nonces were forgotten
+    /// early can be replayed until the end of the window.
+    pub fn with_max_len(self, max_len: usize) -> ReplayGuard {
+        ReplayGuard {
+            max_len: Some(max_len),
+            ..self
+        }
+    }
+
+    /// Number of nonces forgotten before the end of the window to stay within the maximum
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Number of remembered nonces
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Returns `false` if the nonce has already been seen within the window
+    fn insert(&mut self, nonce: Nonce) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        if self.seen.contains_key(&nonce) {
+            return false;
+        }
+        self.make_room();
+        self.seen.insert(nonce, now);
+        self.queue.push_back((now, nonce));
+        true
+    }
+
+    /// Forget oldest nonces until there is room for one more
+    fn make_room(&mut self) {
+        let max_len = match self.max_len {
+            Some(x) => x.max(1),
+            None => return,
+        };
+        while self.queue.len() >= max_len {
+            if let Some((_, nonce)) = self.queue.pop_front() {
+                self.seen.remove(&nonce);
+                self.evicted += 1;
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(t, nonce)) = self.queue.front() {
+            if now.duration_since(t) < self.window {
+                break;
+            }
+            self.queue.pop_front();
+            self.seen.remove(&nonce);
+        }
+    }
+
+    /// Write nonces that are still within the window to `path`, with wall-clock timestamps.
+    /// Writes to a temporary file first and renames it over `path`, so the store is never left half-written.
+    pub fn save(&mut self, path: &Path) -> anyhow::Result<()> {
+        let now = Instant::now();
+        self.expire(now);
+        let wallclock = SystemTime::now();
+        let entries: Vec<(u64, Nonce)> = self
+            .queue
+            .iter()
+            .map(|&(t, nonce)| (unix_millis(wallclock - now.duration_since(t)), nonce))
+            .collect();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(&bco().serialize(&entries)?)?;
+        f.sync_all()?;
+        drop(f);
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Remember nonces saved by [`ReplayGuard::save`] that are still within the window.
+    /// Missing file is not an error.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data = match std::fs::read(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let entries: Vec<(u64, Nonce)> = bco().deserialize(&data)?;
+        let now = Instant::now();
+        let wallclock = unix_millis(SystemTime::now());
+        for (t, nonce) in entries {
+            let age = Duration::from_millis(wallclock.saturating_sub(t));
+            if age >= self.window || self.seen.contains_key(&nonce) {
+                continue;
+            }
+            let t = now.checked_sub(age).unwrap_or(now);
+            self.make_room();
+            self.seen.insert(nonce, t);
+            self.queue.push_back((t, nonce));
+        }
+        Ok(())
+    }
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
 }
 
 use bincode::Options;
-use chacha20poly1305::{aead::Aead, XNonce};
+use chacha20poly1305::aead::{Aead, Payload};
 fn bco() -> impl bincode::Options {
     bincode::DefaultOptions::new()
         .with_big_endian()
         .with_fixint_encoding()
 }
+/// Compress bincode of a message if it is requested, supported and actually makes it shorter
+#[cfg(feature = "compression")]
+fn maybe_compress(buf: Vec<u8>, compress: bool) -> anyhow::Result<(bool, Vec<u8>)> {
+    if compress && buf.len() >= COMPRESSION_THRESHOLD {
+        let compressed = zstd::bulk::compress(&buf[..], 0)?;
+        if compressed.len() < buf.len() {
+            return Ok((true, compressed));
+        }
+    }
+    Ok((false, buf))
+}
+
+#[cfg(not(feature = "compression"))]
+fn maybe_compress(buf: Vec<u8>, _compress: bool) -> anyhow::Result<(bool, Vec<u8>)> {
+    Ok((false, buf))
+}
+
+#[cfg(feature = "compression")]
+fn decompress(buf: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    zstd::bulk::decompress(buf, MAX_MESSAGE_SIZE).map_err(|_| DecryptError::Decompress)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_buf: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    Err(DecryptError::CompressionUnsupported)
+}
+
+/// Prefix `buf` with its length and append zeroes up to a multiple of `pad_to` bytes
+fn pad(buf: Vec<u8>, pad_to: Option<usize>) -> Vec<u8> {
+    let len = 2 + buf.len();
+    let padded_len = match pad_to {
+        Some(n) if n > 1 => len.next_multiple_of(n),
+        _ => len,
+    };
+    let mut padded = Vec::with_capacity(padded_len);
+    padded.extend_from_slice(&(buf.len() as u16).to_be_bytes());
+    padded.extend_from_slice(&buf);
+    padded.resize(padded_len, 0);
+    padded
+}
+
+/// Reverse of [`pad`]
+fn unpad(buf: &[u8]) -> Result<&[u8], DecryptError> {
+    if buf.len() < 2 {
+        return Err(DecryptError::BadPadding);
+    }
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    buf[2..].get(..len).ok_or(DecryptError::BadPadding)
+}
+
+/// [`decrypt`] with the first of `keys` that authenticates the datagram, also returning its index.
+/// The replay set is shared, so switching keys does not let a replay through.
+pub fn decrypt_any(
+    msg: &[u8],
+    keys: &[Cipher],
+    replay_guard: &mut ReplayGuard,
+) -> Result<(Message, usize), DecryptError> {
+    for (i, crypto) in keys.iter().enumerate() {
+        match decrypt(msg, crypto, replay_guard) {
+            Err(DecryptError::AeadFailure) => continue,
+            x => return x.map(|msg| (msg, i)),
+        }
+    }
+    Err(DecryptError::AeadFailure)
+}
+
+/// Header fields of [`Datagram`] authenticated together with the ciphertext
+fn associated_data(magic: u32, version: u8, compressed: bool) -> [u8; 6] {
+    let m = magic.to_be_bytes();
+    [m[0], m[1], m[2], m[3], version, compressed as u8]
+}
+
+/// `compress` is ignored unless built with `compression` feature.
+/// Plaintext is padded to a multiple of `pad_to` bytes, to hide message length.
 pub fn encrypt(
     msg: &Message,
-    crypto: &chacha20poly1305::XChaCha20Poly1305,
+    crypto: &Cipher,
+    compress: bool,
+    pad_to: Option<usize>,
 ) -> anyhow::Result<Vec<u8>> {
     let buf = bco().serialize(msg)?;
-    let mut nonce = [0u8; 24];
-    getrandom::getrandom(&mut nonce[..])?;
-    let data: Vec<u8> = crypto
-        .encrypt(XNonce::from_slice(&nonce), &buf[..])
-        .unwrap();
+    anyhow::ensure!(
+        buf.len() <= MAX_MESSAGE_SIZE,
+        "Message of {} bytes exceeds maximum message size of {} bytes",
+        buf.len(),
+        MAX_MESSAGE_SIZE
+    );
+    seal(buf, crypto, compress, pad_to)
+}
+
+/// Encrypt serialized message `buf` into a datagram
+fn seal(
+    buf: Vec<u8>,
+    crypto: &Cipher,
+    compress: bool,
+    pad_to: Option<usize>,
+) -> anyhow::Result<Vec<u8>> {
+    let (compressed, buf) = maybe_compress(buf, compress)?;
+    let buf = pad(buf, pad_to);
+    let aad = associated_data(crypto.magic, PROTOCOL_VERSION, compressed);
+    let payload = Payload {
+        msg: &buf[..],
+        aad: &aad[..],
+    };
+    let (nonce, data) = match crypto.aead {
+        AeadCipher::XChaCha20Poly1305(ref c) => {
+            let mut nonce = [0u8; 24];
+            crypto.make_nonce(&mut nonce[..])?;
+            let data: Vec<u8> = c
+                .encrypt(GenericArray::from_slice(&nonce), payload)
+                .map_err(|_| anyhow::anyhow!("AEAD encryption failed"))?;
+            (DatagramNonce::XChaCha20Poly1305(nonce), data)
+        }
+        AeadCipher::Aes256Gcm(ref c) => {
+            let mut nonce = [0u8; 12];
+            crypto.make_nonce(&mut nonce[..])?;
+            let data: Vec<u8> = c
+                .encrypt(GenericArray::from_slice(&nonce), payload)
+                .map_err(|_| anyhow::anyhow!("AEAD encryption failed"))?;
+            (DatagramNonce::Aes256Gcm(nonce), data)
+        }
+    };
     let h = Datagram {
-        magic: MAGIC,
+        magic: crypto.magic,
+        version: PROTOCOL_VERSION,
         nonce,
+        compressed,
         data,
     };
-    let dg = bco().serialize(&h).unwrap();
+    let dg = bco().serialize(&h)?;
     Ok(dg)
 }
 
+/// Why a datagram is not a valid moshudp message
+#[derive(Debug)]
+pub enum DecryptError {
+    /// Not a moshudp datagram at all, e.g. mosh traffic
+    BadMagic,
+    /// moshudp datagram of another protocol version
+    BadVersion(u8),
+    /// Peer is configured with another `--cipher`
+    CipherMismatch,
+    /// Wrong key or tampered datagram
+    AeadFailure,
+    /// Datagram with this nonce has already been received
+    Replay,
+    /// Malformed datagram or message
+    Deserialize(bincode::Error),
+    /// Length prefix of padded plaintext is out of bounds
+    BadPadding,
+    /// Message is longer than [`MAX_MESSAGE_SIZE`], the peer is buggy or malicious
+    TooLarge(usize),
+    Decompress,
+    CompressionUnsupported,
+}
+
+impl std::fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecryptError::BadMagic => write!(f, "Invalid magic"),
+            DecryptError::BadVersion(v) => write!(f, "unsupported protocol version {}", v),
+            DecryptError::CipherMismatch => write!(f, "Peer uses different cipher"),
+            DecryptError::AeadFailure => write!(f, "Decryption failed"),
+            DecryptError::Replay => write!(f, "Replay attack"),
+            DecryptError::Deserialize(e) => write!(f, "Malformed datagram: {}", e),
+            DecryptError::BadPadding => write!(f, "Malformed padding"),
+            DecryptError::TooLarge(len) => write!(
+                f,
+                "Message of {} bytes exceeds maximum message size of {} bytes",
+                len, MAX_MESSAGE_SIZE
+            ),
+            DecryptError::Decompress => write!(f, "Failed to decompress message"),
+            DecryptError::CompressionUnsupported => write!(
+                f,
+                "Peer sent compressed message, but compression support is not built in"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+impl From<bincode::Error> for DecryptError {
+    fn from(e: bincode::Error) -> Self {
+        DecryptError::Deserialize(e)
+    }
+}
+
 pub fn decrypt(
     msg: &[u8],
-    crypto: &chacha20poly1305::XChaCha20Poly1305,
-    past_nonces: &mut FxHashSet<Nonce>,
-) -> anyhow::Result<Message> {
-    let h: Datagram = bco().with_limit(1024).deserialize(msg)?;
-    if h.magic != MAGIC {
-        anyhow::bail!("Invalid magic");
-    }
-    let buf = crypto
-        .decrypt(XNonce::from_slice(&h.nonce), &h.data[..])
-        .map_err(|_| anyhow::anyhow!("Decryption failed"))?;
+    crypto: &Cipher,
+    replay_guard: &mut ReplayGuard,
+) -> Result<Message, DecryptError> {
+    // Check the part that stays the same across versions first, to report outdated peers clearly.
+    // Datagrams too short to have it are not ours either.
+    let (magic, version): (u32, u8) = bco()
+        .allow_trailing_bytes()
+        .deserialize(msg)
+        .map_err(|_| DecryptError::BadMagic)?;
+    if magic != crypto.magic {
+        return Err(DecryptError::BadMagic);
+    }
+    if version != PROTOCOL_VERSION {
+        return Err(DecryptError::BadVersion(version));
+    }
+    // bincode checks length prefixes against the limit before allocating,
+    // so a bogus length of `data` cannot cause a large allocation
+    let h: Datagram = bco().with_limit(MAX_DATAGRAM_SIZE).deserialize(msg)?;
+    let aad = associated_data(h.magic, h.version, h.compressed);
+    let payload = Payload {
+        msg: &h.data[..],
+        aad: &aad[..],
+    };
+    let buf = match (&crypto.aead, &h.nonce) {
+        (AeadCipher::XChaCha20Poly1305(c), DatagramNonce::XChaCha20Poly1305(nonce)) => {
+            c.decrypt(GenericArray::from_slice(nonce), payload)
+        }
+        (AeadCipher::Aes256Gcm(c), DatagramNonce::Aes256Gcm(nonce)) => {
+            c.decrypt(GenericArray::from_slice(nonce), payload)
+        }
+        _ => return Err(DecryptError::CipherMismatch),
+    }
+    .map_err(|_| DecryptError::AeadFailure)?;
     //eprintln!("nonce={:?}",h.nonce);
-    if !past_nonces.insert(h.nonce) {
-        anyhow::bail!("Replay attack");
+    if !replay_guard.insert(h.nonce.replay_key()) {
+        return Err(DecryptError::Replay);
+    }
+    let buf = unpad(&buf[..])?;
+    let buf = if h.compressed {
+        decompress(buf)?
+    } else {
+        buf.to_vec()
+    };
+    if buf.len() > MAX_MESSAGE_SIZE {
+        return Err(DecryptError::TooLarge(buf.len()));
+    }
+    Ok(bco()
+        .with_limit(MAX_MESSAGE_SIZE as u64)
+        .deserialize(&buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn arb_message() -> impl Strategy<Value = Message> {
+        prop_oneof![
+            any::<u64>().prop_map(|seq| Message::Ping { seq }),
+            any::<u64>().prop_map(|seq| Message::Pong { seq }),
+            (any::<u64>(), proptest::option::of(any::<CookieToken>()))
+                .prop_map(|(sessid, cookie)| Message::StartServer { sessid, cookie }),
+            ".{0,100}".prop_map(|key| Message::ServerStarted { key }),
+            ".{0,100}".prop_map(|msg| Message::Failed { msg }),
+            any::<u64>().prop_map(|sessid| Message::UpdateAddress { sessid }),
+            any::<CookieToken>().prop_map(|token| Message::Cookie { token }),
+            Just(Message::Status),
+            (any::<u64>(), any::<u64>(), ".{0,20}", any::<[u64; 6]>()).prop_map(
+                |(active_sessions, uptime_secs, version, c)| Message::StatusReply {
+                    active_sessions,
+                    uptime_secs,
+                    version,
+                    counters: Counters {
+                        traffic: Traffic {
+                            packets_in: c[0],
+                            packets_out: c[1],
+                            bytes_in: c[2],
+                            bytes_out: c[3],
+                        },
+                        decrypt_failures: c[4],
+                        replay_hits: c[5],
+                    },
+                }
+            ),
+            any::<u64>().prop_map(|sessid| Message::Bye { sessid }),
+        ]
+    }
+
+    fn arb_cipher_kind() -> impl Strategy<Value = CipherKind> {
+        prop_oneof![
+            Just(CipherKind::XChaCha20Poly1305),
+            Just(CipherKind::Aes256Gcm),
+        ]
+    }
+
+    fn replay_guard() -> ReplayGuard {
+        ReplayGuard::new(Duration::from_secs(3600))
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(
+            msg in arb_message(),
+            kind in arb_cipher_kind(),
+            key in any::<[u8; 32]>(),
+            compress in any::<bool>(),
+            pad_to in proptest::option::of(any::<u8>()),
+        ) {
+            let crypto = Cipher::new(kind, &key);
+            let pad_to = pad_to.map(usize::from);
+            let pkt = encrypt(&msg, &crypto, compress, pad_to).unwrap();
+            let decrypted = decrypt(&pkt, &crypto, &mut replay_guard()).unwrap();
+            prop_assert_eq!(decrypted, msg);
+        }
+
+        #[test]
+        fn padding_hides_length(
+            kind in arb_cipher_kind(),
+            key in any::<[u8; 32]>(),
+            len in any::<u8>(),
+        ) {
+            let crypto = Cipher::new(kind, &key);
+            let short = encrypt(&Message::failed(""), &crypto, false, Some(512)).unwrap();
+            let msg = Message::failed("x".repeat(len as usize));
+            let long = encrypt(&msg, &crypto, false, Some(512)).unwrap();
+            prop_assert_eq!(short.len(), long.len());
+        }
+
+        #[test]
+        fn wrong_key_fails(
+            msg in arb_message(),
+            kind in arb_cipher_kind(),
+            key in any::<[u8; 32]>(),
+            other_key in any::<[u8; 32]>(),
+        ) {
+            prop_assume!(key != other_key);
+            let pkt = encrypt(&msg, &Cipher::new(kind, &key), false, None).unwrap();
+            let err = decrypt(&pkt, &Cipher::new(kind, &other_key), &mut replay_guard());
+            prop_assert!(matches!(err, Err(DecryptError::AeadFailure)));
+        }
+
+        #[test]
+        fn replay_rejected(
+            msg in arb_message(),
+            kind in arb_cipher_kind(),
+            key in any::<[u8; 32]>(),
+        ) {
+            let crypto = Cipher::new(kind, &key);
+            let pkt = encrypt(&msg, &crypto, false, None).unwrap();
+            let mut replay_guard = replay_guard();
+            prop_assert!(decrypt(&pkt, &crypto, &mut replay_guard).is_ok());
+            let err = decrypt(&pkt, &crypto, &mut replay_guard);
+            prop_assert!(matches!(err, Err(DecryptError::Replay)));
+        }
+    }
+
+    #[test]
+    fn counter_nonces() {
+        for kind in [CipherKind::XChaCha20Poly1305, CipherKind::Aes256Gcm] {
+            let crypto = Cipher::new(kind, &[7; 32])
+                .with_nonce_scheme(NonceScheme::Counter)
+                .unwrap();
+            let mut replay_guard = replay_guard();
+            let nonces: Vec<Nonce> = (0..3)
+                .map(|_| {
+                    let pkt = encrypt(&Message::Status, &crypto, false, None).unwrap();
+                    assert_eq!(
+                        decrypt(&pkt, &crypto, &mut replay_guard).unwrap(),
+                        Message::Status
+                    );
+                    bco()
+                        .deserialize::<Datagram>(&pkt)
+                        .unwrap()
+                        .nonce
+                        .replay_key()
+                })
+                .collect();
+            let prefix_len = match kind {
+                CipherKind::XChaCha20Poly1305 => 8,
+                CipherKind::Aes256Gcm => 4,
+            };
+            let counter =
+                |n: &Nonce| u64::from_be_bytes(n[prefix_len..prefix_len + 8].try_into().unwrap());
+            assert_eq!(nonces[0][..prefix_len], nonces[2][..prefix_len]);
+            assert_eq!(counter(&nonces[1]), counter(&nonces[0]).wrapping_add(1));
+            assert_eq!(counter(&nonces[2]), counter(&nonces[0]).wrapping_add(2));
+        }
+    }
+
+    #[test]
+    fn long_failure_message_roundtrip() {
+        let crypto = Cipher::new(CipherKind::Aes256Gcm, &[7; 32]);
+        let msg = Message::failed("mosh-server: ".repeat(1000));
+        let pkt = encrypt(&msg, &crypto, false, None).unwrap();
+        assert_eq!(decrypt(&pkt, &crypto, &mut replay_guard()).unwrap(), msg);
+        match msg {
+            Message::Failed { msg } => assert!(msg.len() > 2000),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn too_large_message_rejected() {
+        let crypto = Cipher::new(CipherKind::XChaCha20Poly1305, &[7; 32]);
+        let msg = Message::Failed {
+            msg: "x".repeat(MAX_MESSAGE_SIZE),
+        };
+        let err = encrypt(&msg, &crypto, false, None).unwrap_err();
+        assert!(err.to_string().contains("exceeds maximum message size"));
+        let buf = bco().serialize(&msg).unwrap();
+        let len = buf.len();
+        let pkt = seal(buf, &crypto, false, None).unwrap();
+        let err = decrypt(&pkt, &crypto, &mut replay_guard());
+        assert!(matches!(err, Err(DecryptError::TooLarge(x)) if x == len));
+    }
+
+    #[test]
+    fn other_magic_rejected() {
+        let crypto = Cipher::new(CipherKind::XChaCha20Poly1305, &[7; 32]);
+        let other = Cipher::new(CipherKind::XChaCha20Poly1305, &[7; 32])
+            .with_magic(namespace_magic("other"));
+        let pkt = encrypt(&Message::Status, &other, false, None).unwrap();
+        let err = decrypt(&pkt, &crypto, &mut replay_guard());
+        assert!(matches!(err, Err(DecryptError::BadMagic)));
+        assert_eq!(
+            decrypt(&pkt, &other, &mut replay_guard()).unwrap(),
+            Message::Status
+        );
+    }
+
+    #[test]
+    fn decrypt_any_finds_key() {
+        let old = Cipher::new(CipherKind::XChaCha20Poly1305, &[7; 32]);
+        let new = Cipher::new(CipherKind::XChaCha20Poly1305, &[8; 32]);
+        let keys = [new, old];
+        let mut replay_guard = replay_guard();
+        let pkt = encrypt(&Message::Status, &keys[1], false, None).unwrap();
+        let (msg, i) = decrypt_any(&pkt, &keys, &mut replay_guard).unwrap();
+        assert_eq!((msg, i), (Message::Status, 1));
+        let err = decrypt_any(&pkt, &keys, &mut replay_guard);
+        assert!(matches!(err, Err(DecryptError::Replay)));
+        let other = Cipher::new(CipherKind::XChaCha20Poly1305, &[9; 32]);
+        let pkt = encrypt(&Message::Status, &other, false, None).unwrap();
+        let err = decrypt_any(&pkt, &keys, &mut replay_guard);
+        assert!(matches!(err, Err(DecryptError::AeadFailure)));
+    }
+
+    #[test]
+    fn debug_redacts_key() {
+        let msg = Message::ServerStarted {
+            key: "c3R1YmtleXN0dWJrZXkxMg".to_owned(),
+        };
+        assert_eq!(format!("{:?}", msg), "ServerStarted { key: <redacted> }");
+    }
+
+    #[test]
+    fn max_nonces_evicts_oldest() {
+        let crypto = Cipher::new(CipherKind::XChaCha20Poly1305, &[7; 32]);
+        let mut guard = replay_guard().with_max_len(2);
+        let pkts: Vec<Vec<u8>> = (0..3)
+            .map(|seq| encrypt(&Message::Ping { seq }, &crypto, false, None).unwrap())
+            .collect();
+        for pkt in &pkts {
+            decrypt(pkt, &crypto, &mut guard).unwrap();
+        }
+        assert_eq!((guard.len(), guard.evicted()), (2, 1));
+        let err = decrypt(&pkts[2], &crypto, &mut guard);
+        assert!(matches!(err, Err(DecryptError::Replay)));
+        // Forgotten early, so accepted again
+        assert!(decrypt(&pkts[0], &crypto, &mut guard).is_ok());
+    }
+
+    #[test]
+    fn replay_rejected_after_restore() {
+        let crypto = Cipher::new(CipherKind::XChaCha20Poly1305, &[7; 32]);
+        let pkt = encrypt(&Message::Ping { seq: 1 }, &crypto, false, None).unwrap();
+        let path = std::env::temp_dir().join(format!("moshudp-nonces-{}", std::process::id()));
+        let mut guard = replay_guard();
+        decrypt(&pkt, &crypto, &mut guard).unwrap();
+        guard.save(&path).unwrap();
+        let mut restored = replay_guard();
+        restored.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.len(), 1);
+        let err = decrypt(&pkt, &crypto, &mut restored);
+        assert!(matches!(err, Err(DecryptError::Replay)));
     }
-    Ok(bco().with_limit(1024).deserialize(&buf)?)
 }
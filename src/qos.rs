@@ -0,0 +1,47 @@
+//! `--dscp` marking of datagrams sent to the network peer, for routers to prioritize them.
+
+use std::net::{SocketAddr, UdpSocket};
+
+/// Sets DSCP bits of traffic class of datagrams sent from `socket`. IPv6 sockets also get
+/// `IP_TOS` for IPv4-mapped peers, where supported.
+#[cfg(unix)]
+pub(crate) fn set_dscp(socket: &UdpSocket, dscp: u8) -> std::io::Result<()> {
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    let setsockopt = |level: libc::c_int, name: libc::c_int| {
+        // DSCP is the upper 6 bits, ECN bits are left zero
+        let value = libc::c_int::from(dscp) << 2;
+        // Safety: the option value is a live c_int and its size is passed along
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    };
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => setsockopt(libc::IPPROTO_IP, libc::IP_TOS),
+        SocketAddr::V6(_) => {
+            setsockopt(libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?;
+            let _ = setsockopt(libc::IPPROTO_IP, libc::IP_TOS);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_dscp(_socket: &UdpSocket, _dscp: u8) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "DSCP marking is not supported on this platform",
+    ))
+}
@@ -0,0 +1,17 @@
+//! Telling transient failures to send a datagram from ones that mean the socket is unusable
+
+use std::io;
+
+#[cfg(unix)]
+const ENOBUFS: i32 = nix::libc::ENOBUFS;
+#[cfg(windows)]
+const ENOBUFS: i32 = windows_sys::Win32::Networking::WinSock::WSAENOBUFS as i32;
+
+/// Out of buffer space under a burst, or interrupted: drop the datagram like the network
+/// would and keep the session
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    ) || e.raw_os_error() == Some(ENOBUFS)
+}
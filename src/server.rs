@@ -1,220 +1,1604 @@
 use std::{
     ffi::OsStr,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
-    os::unix::prelude::AsRawFd,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::unix::{
+        net::UnixDatagram,
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-use chacha20poly1305::XChaCha20Poly1305;
-use fxhash::FxHashSet;
-use nix::poll::{poll, PollFd, PollFlags};
+use fxhash::FxHashMap;
+use nix::{
+    sys::{
+        signal::{kill, Signal},
+        socket::{getsockopt, sockopt, SockType},
+    },
+    unistd::{ForkResult, Group, Pid, User},
+};
+
+use log::{debug, error, info, warn};
 
-use crate::protocol::{Message, Nonce};
+use crate::batch::RecvBatch;
+use crate::metrics::Metrics;
+use crate::mtu::MtuCheck;
+use crate::poller::{PlatformPoller, Pollable, Poller};
+use crate::protocol::{Cipher, CookieJar, Counters, DecryptError, Message, ReplayGuard, Traffic};
+use crate::send_error::is_transient;
+use crate::sockbuf::SocketBuffers;
+use crate::throttle::{Throttle, ThrottleOptions};
+use crate::trace::{self, Payload};
 use std::os::unix::ffi::OsStrExt;
 
 const UPDATE_ADDRESS_COOLDOWN: Duration = Duration::from_millis(333);
 const MOSH_SERVER_TIMEOUT: Duration = Duration::from_secs(60);
+const MOSH_SERVER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of source addresses for `RateLimiter` and `KeyMismatchDetector` to track before
+/// forgetting idle ones
+const RATE_LIMITER_MAX_SOURCES: usize = 4096;
+/// Undecryptable datagrams from one address that suggest a client with the wrong key. A client
+/// resends its first request several times before giving up.
+const KEY_MISMATCH_BURST: u32 = 3;
+/// Period over which `KEY_MISMATCH_BURST` is counted
+const KEY_MISMATCH_WINDOW: Duration = Duration::from_secs(60);
+/// How often to write remembered nonces to `--nonce-store`
+const NONCE_STORE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Address family for communication with mosh-server
+#[derive(Clone, Copy)]
+pub enum Loopback {
+    V4,
+    V6,
+}
+
+impl Loopback {
+    fn addr(self) -> IpAddr {
+        match self {
+            Loopback::V4 => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Loopback::V6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        }
+    }
+}
+
+impl FromStr for Loopback {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v4" => Ok(Loopback::V4),
+            "v6" => Ok(Loopback::V6),
+            _ => anyhow::bail!("Unknown loopback {}, use v4 or v6", s),
+        }
+    }
+}
+
+/// UDP ports mosh-server may choose from, `LOW:HIGH` or a single port
+#[derive(Clone, Copy)]
+pub struct PortRange {
+    pub low: u16,
+    pub high: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (low, high) = match s.split_once(':') {
+            Some((low, high)) => (low.parse()?, high.parse()?),
+            None => {
+                let port = s.parse()?;
+                (port, port)
+            }
+        };
+        anyhow::ensure!(
+            low != 0 && low <= high,
+            "Invalid port range {}, use LOW:HIGH",
+            s
+        );
+        Ok(PortRange { low, high })
+    }
+}
+
+/// Where mosh traffic of a session goes: UDP address, or `unix:PATH` for a Unix datagram socket
+#[derive(Clone, Debug)]
+pub enum Backend {
+    Udp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => anyhow::bail!("Empty socket path in {}", s),
+            Some(path) => Ok(Backend::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Backend::Udp)
+                .map_err(|_| anyhow::anyhow!("Invalid address {}, use IP:PORT or unix:PATH", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Udp(addr) => addr.fmt(f),
+            Backend::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
 
 pub struct Server {
-    server_socket: UdpSocket,
-    crypto: XChaCha20Poly1305,
-    mosh: Option<MoshState>,
-    past_nonces: FxHashSet<Nonce>,
-    recent_client_addr: Option<SocketAddr>,
+    /// Listening sockets, clients may arrive on any of them
+    server_sockets: Vec<UdpSocket>,
+    /// Primary key first, then ones also accepted during key rotation
+    keys: Vec<Cipher>,
+    /// Compress outgoing messages, if built with `compression` feature
+    compress: bool,
+    /// Pad outgoing messages to a multiple of this size
+    pad_to: Option<usize>,
+    sessions: FxHashMap<u64, MoshState>,
+    /// Index of `sessions` by client address, to route incoming non-control traffic
+    client_sessions: FxHashMap<SocketAddr, u64>,
+    replay_guard: ReplayGuard,
+    /// File to save `replay_guard` to, to keep replay protection across restarts
+    nonce_store: Option<PathBuf>,
+    next_nonce_store_flush: Instant,
+    /// Return-routability check before starting mosh-server
+    cookies: CookieJar,
+    /// Limits decryption attempts from addresses without a session
+    rate_limiter: Option<RateLimiter>,
+    key_mismatch: KeyMismatchDetector,
+    /// Bandwidth limit of each session's traffic towards client
+    throttle: Option<ThrottleOptions>,
+    mtu_check: Option<MtuCheck>,
+    socket_buffers: SocketBuffers,
     update_address_cooldown: Instant,
+    buffer_size: usize,
+    /// Appended to mosh-server command line after the fixed options
+    mosh_server_args: Vec<String>,
+    /// Program and arguments to run instead of mosh-server, with placeholders
+    spawn_cmd: Vec<String>,
+    connect_line: ConnectLine,
+    /// Kill mosh-server if it does not finish startup in this time
+    spawn_timeout: Duration,
+    /// Passed to mosh-server as `-p`, any port if `None`
+    mosh_port_range: Option<PortRange>,
+    /// Where mosh-server listens: loopback or `--backend-addr`
+    mosh_addr: IpAddr,
+    forward_to: Option<Backend>,
+    idle_timeout: Option<Duration>,
+    /// Stop sessions whose client sends no mosh traffic for this long after start
+    handshake_timeout: Option<Duration>,
+    /// Refuse to start more mosh-servers than this
+    max_sessions: Option<usize>,
+    /// Serve only one session and return from `serve` when it ends
+    once: bool,
+    /// Whether any session has been started, for `once`
+    had_session: bool,
+    /// Sessions were added or removed since sockets were last given to the poller
+    sessions_changed: bool,
+    next_mosh_server_check: Instant,
+    /// For uptime in `StatusReply`
+    started: Instant,
+    counters: Counters,
+    /// Log `counters` this often
+    stats_interval: Option<Duration>,
+    next_stats: Instant,
+    /// `--metrics-addr` endpoint, gets `counters` after every poll
+    metrics: Option<Metrics>,
+    /// Set by SIGTERM or SIGINT handler
+    shutdown: Arc<AtomicBool>,
 }
 
 struct MoshState {
-    socket: UdpSocket,
+    socket: MoshSocket,
     key: String,
-    sessid: u64,
+    /// Which of server's keys the client uses
+    key_index: usize,
+    client_addr: SocketAddr,
+    /// Index of the listening socket the client is reachable through
+    listener: usize,
+    update_address_cooldown: Instant,
+    /// Detached mosh-server process, if its pid was found in the output
+    pid: Option<Pid>,
+    last_activity: Instant,
+    throttle: Option<Throttle>,
+    /// Forwarded mosh traffic of this session
+    traffic: Traffic,
+    /// When mosh-server was started, for `--handshake-timeout`
+    created: Instant,
+}
+
+impl MoshState {
+    /// Whether mosh-server is still running and its socket has not been refused by it
+    fn is_alive(&mut self) -> bool {
+        if matches!(self.pid, Some(pid) if kill(pid, None).is_err()) {
+            // Don't let Drop signal a possibly reused pid
+            self.pid = None;
+            return false;
+        }
+        matches!(self.socket.take_error(), Ok(None))
+    }
+}
+
+impl Drop for MoshState {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            let _ = kill(pid, Signal::SIGTERM);
+        }
+    }
+}
+
+/// Connected socket towards mosh-server of a session
+enum MoshSocket {
+    Udp(UdpSocket),
+    Unix(UnixRelay),
+}
+
+impl MoshSocket {
+    fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            MoshSocket::Udp(x) => x.send(buf),
+            MoshSocket::Unix(x) => x.socket.send(buf),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            MoshSocket::Udp(x) => x.recv(buf),
+            MoshSocket::Unix(x) => x.socket.recv(buf),
+        }
+    }
+
+    fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        match self {
+            MoshSocket::Udp(x) => x.take_error(),
+            MoshSocket::Unix(x) => x.socket.take_error(),
+        }
+    }
+
+    /// Peer for log messages
+    fn peer(&self) -> String {
+        match self {
+            MoshSocket::Udp(x) => x
+                .peer_addr()
+                .map_or_else(|_| "?".to_owned(), |x| x.to_string()),
+            MoshSocket::Unix(x) => Backend::Unix(x.peer.clone()).to_string(),
+        }
+    }
+}
+
+impl AsRawFd for MoshSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            MoshSocket::Udp(x) => x.as_raw_fd(),
+            MoshSocket::Unix(x) => x.socket.as_raw_fd(),
+        }
+    }
+}
+
+/// Unix datagram socket connected to `peer`. Bound to a temporary path, as replies need an
+/// address to come back to; the path is removed on drop.
+struct UnixRelay {
+    socket: UnixDatagram,
+    path: PathBuf,
+    peer: PathBuf,
+}
+
+impl UnixRelay {
+    fn connect(peer: &Path) -> anyhow::Result<UnixRelay> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "moshudp-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Left over from a crashed process with the same pid
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)
+            .map_err(|e| anyhow::anyhow!("Cannot bind {}: {}", path.display(), e))?;
+        let relay = UnixRelay {
+            socket,
+            path,
+            peer: peer.to_owned(),
+        };
+        relay.socket.connect(peer).map_err(|e| {
+            anyhow::anyhow!("Cannot reach mosh-server at unix:{}: {}", peer.display(), e)
+        })?;
+        Ok(relay)
+    }
+}
+
+impl Drop for UnixRelay {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Where to get the listening sockets from
+pub enum Listen {
+    Bind(Vec<SocketAddr>),
+    /// Sockets passed by systemd socket activation
+    Systemd,
+    /// Socket passed by inetd as standard input, in `wait` mode
+    Inetd,
+}
+
+/// How to find port and key in the output of mosh-server or `--spawn-cmd` program
+pub struct ConnectLine {
+    /// Beginning of the line with port and key
+    pub prefix: String,
+    /// Index of whitespace-separated word with port, counting from 0 including the prefix
+    pub port_field: usize,
+    /// Index of whitespace-separated word with key
+    pub key_field: usize,
+}
+
+impl Default for ConnectLine {
+    /// `MOSH CONNECT <port> <key>`
+    fn default() -> Self {
+        ConnectLine {
+            prefix: "MOSH CONNECT".to_owned(),
+            port_field: 2,
+            key_field: 3,
+        }
+    }
+}
+
+/// Settings of [`Server`] that come from command line
+pub struct ServerOptions {
+    pub buffer_size: usize,
+    pub replay_window: Duration,
+    /// Forget oldest nonces early to remember at most this many
+    pub max_nonces: Option<usize>,
+    /// File to periodically save remembered nonces to and load them from on startup
+    pub nonce_store: Option<PathBuf>,
+    /// Keys accepted besides the primary one, during key rotation
+    pub extra_keys: Vec<Cipher>,
+    /// DSCP value to mark datagrams to clients with
+    pub dscp: Option<u8>,
+    /// Kernel buffer sizes of listening and mosh-server-facing sockets
+    pub socket_buffers: SocketBuffers,
+    /// Network interface to pin listening sockets to
+    pub bind_device: Option<String>,
+    /// Appended to mosh-server command line after the fixed options
+    pub mosh_server_args: Vec<String>,
+    /// Program and arguments to run instead of mosh-server. `{ip}` is replaced with
+    /// mosh-server address and `{port}` with a free UDP port on it.
+    pub spawn_cmd: Vec<String>,
+    pub connect_line: ConnectLine,
+    /// Kill mosh-server if it does not finish startup in this time. Other clients are not served while waiting
+    pub spawn_timeout: Duration,
+    /// Passed to mosh-server as `-p`, any port if `None`
+    pub mosh_port_range: Option<PortRange>,
+    pub loopback: Loopback,
+    /// mosh-server address other than loopback, e.g. in another network namespace.
+    /// Passed as `-i` and forwarded to instead of loopback.
+    pub backend_addr: Option<IpAddr>,
+    /// Forward traffic of every session to this address instead of starting mosh-server
+    pub forward_to: Option<Backend>,
+    /// Stop sessions without traffic for this long
+    pub idle_timeout: Option<Duration>,
+    /// Stop sessions whose client sends no mosh traffic for this long after mosh-server start
+    pub handshake_timeout: Option<Duration>,
+    /// Refuse to start more mosh-servers than this
+    pub max_sessions: Option<usize>,
+    /// Serve only one session and return from [`Server::serve`] when it ends
+    pub once: bool,
+    /// Compress outgoing messages, if built with `compression` feature
+    pub compress: bool,
+    /// Pad outgoing messages to a multiple of this size
+    pub pad_to: Option<usize>,
+    /// Decryption attempts per second allowed from each source address without a session
+    pub max_rate: Option<f64>,
+    /// Bandwidth limit of each session's traffic towards client
+    pub throttle: Option<ThrottleOptions>,
+    /// Warn about datagrams to clients that exceed this size with IP and UDP headers
+    pub mtu: Option<usize>,
+    /// Log traffic counters this often
+    pub stats_interval: Option<Duration>,
+    /// Serve Prometheus metrics over HTTP on this address
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+/// Fork into background, detach from terminal and redirect stdio to /dev/null.
+/// Parent process writes child's pid to `pidfile` and exits.
+pub fn daemonize(pidfile: Option<&Path>) -> anyhow::Result<()> {
+    // Safety: called before any threads are started
+    match unsafe { nix::unistd::fork() }? {
+        ForkResult::Parent { child } => {
+            if let Some(pidfile) = pidfile {
+                if let Err(e) = std::fs::write(pidfile, format!("{}\n", child)) {
+                    let _ = kill(child, Signal::SIGKILL);
+                    anyhow::bail!("Failed to write pid file {}: {}", pidfile.display(), e);
+                }
+            }
+            std::process::exit(0);
+        }
+        ForkResult::Child => (),
+    }
+    nix::unistd::setsid()?;
+    nix::unistd::chdir("/")?;
+    let devnull = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        nix::unistd::dup2(devnull.as_raw_fd(), fd)?;
+    }
+    Ok(())
+}
+
+/// Switch to `user` and `group` (by default, the user's primary group), e.g. after binding a
+/// privileged port. Spawned mosh-servers run as this user.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        nix::unistd::geteuid().is_root(),
+        "--user and --group require starting as root"
+    );
+    let user = match user {
+        Some(name) => match User::from_name(name)? {
+            Some(x) => Some(x),
+            None => anyhow::bail!("Unknown user {}", name),
+        },
+        None => None,
+    };
+    let gid = match (group, &user) {
+        (Some(name), _) => match Group::from_name(name)? {
+            Some(x) => x.gid,
+            None => anyhow::bail!("Unknown group {}", name),
+        },
+        (None, Some(user)) => user.gid,
+        (None, None) => nix::unistd::getgid(),
+    };
+    // Supplementary groups of root must not be kept
+    #[cfg(not(any(target_os = "ios", target_os = "macos", target_os = "redox")))]
+    nix::unistd::setgroups(&[gid])
+        .map_err(|e| anyhow::anyhow!("Failed to drop supplementary groups: {}", e))?;
+    nix::unistd::setgid(gid).map_err(|e| anyhow::anyhow!("Failed to set group: {}", e))?;
+    if let Some(user) = user {
+        nix::unistd::setuid(user.uid)
+            .map_err(|e| anyhow::anyhow!("Failed to set user {}: {}", user.name, e))?;
+        // For mosh-server to start the right shell in the right place
+        std::env::set_var("HOME", &user.dir);
+        std::env::set_var("USER", &user.name);
+        std::env::set_var("LOGNAME", &user.name);
+        std::env::set_var("SHELL", &user.shell);
+    }
+    info!(
+        "Dropped privileges to uid {} gid {}",
+        nix::unistd::getuid(),
+        gid
+    );
+    Ok(())
+}
+
+/// Whether systemd socket activation environment variables are meant for this process
+pub fn systemd_activated() -> bool {
+    match std::env::var("LISTEN_PID") {
+        Ok(pid) => pid.parse::<i32>().ok() == Some(nix::unistd::getpid().as_raw()),
+        Err(_) => false,
+    }
+}
+
+/// Adopt the sockets passed by systemd as file descriptors starting from 3
+fn systemd_sockets() -> anyhow::Result<Vec<UdpSocket>> {
+    const SD_LISTEN_FDS_START: RawFd = 3;
+    if !systemd_activated() {
+        anyhow::bail!("LISTEN_PID is not set or does not match, no socket passed by systemd");
+    }
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    let n = match listen_fds.and_then(|x| x.parse::<RawFd>().ok()) {
+        Some(0) => anyhow::bail!("No sockets passed by systemd"),
+        Some(n) => n,
+        None => anyhow::bail!("LISTEN_FDS is not set or invalid"),
+    };
+    let fds = SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n;
+    for fd in fds.clone() {
+        match getsockopt(fd, sockopt::SockType) {
+            Ok(SockType::Datagram) => (),
+            Ok(_) => anyhow::bail!("Socket {} passed by systemd is not a datagram socket", fd),
+            Err(e) => anyhow::bail!("Invalid socket {} passed by systemd: {}", fd, e),
+        }
+    }
+    // Don't let mosh-server think sockets are meant for it
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    // Safety: the fds are passed to us by systemd and not used anywhere else
+    Ok(fds
+        .map(|fd| unsafe { UdpSocket::from_raw_fd(fd) })
+        .collect())
+}
+
+/// Adopt the UDP socket inetd passes as standard input. It is moved to another fd, so that
+/// `--daemon` or anything else that reopens standard input does not close it.
+fn inetd_socket() -> anyhow::Result<UdpSocket> {
+    const STDIN: RawFd = 0;
+    match getsockopt(STDIN, sockopt::SockType) {
+        Ok(SockType::Datagram) => (),
+        Ok(_) => anyhow::bail!(
+            "Standard input is not a datagram socket, use `dgram udp wait` in inetd.conf"
+        ),
+        Err(_) => anyhow::bail!("Standard input is not a socket, --inetd must be started by inetd"),
+    }
+    let fd = nix::fcntl::fcntl(STDIN, nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(3))?;
+    // Safety: the fd is a fresh duplicate not used anywhere else
+    Ok(unsafe { UdpSocket::from_raw_fd(fd) })
 }
 
 impl Server {
-    pub fn new(sa: SocketAddr, crypto: XChaCha20Poly1305) -> anyhow::Result<Server> {
+    pub fn new(listen: Listen, crypto: Cipher, opts: ServerOptions) -> anyhow::Result<Server> {
+        let ServerOptions {
+            buffer_size,
+            replay_window,
+            max_nonces,
+            nonce_store,
+            mosh_server_args,
+            spawn_cmd,
+            connect_line,
+            spawn_timeout,
+            mosh_port_range,
+            loopback,
+            backend_addr,
+            forward_to,
+            idle_timeout,
+            handshake_timeout,
+            max_sessions,
+            once,
+            compress,
+            max_rate,
+            throttle,
+            mtu,
+            pad_to,
+            extra_keys,
+            dscp,
+            socket_buffers,
+            bind_device,
+            stats_interval,
+            metrics_addr,
+        } = opts;
+        let server_sockets = match listen {
+            Listen::Bind(addrs) => {
+                anyhow::ensure!(!addrs.is_empty(), "No addresses to listen on");
+                let mut sockets = Vec::with_capacity(addrs.len());
+                for sa in addrs {
+                    match UdpSocket::bind(sa) {
+                        Ok(s) => sockets.push(s),
+                        Err(e) => anyhow::bail!("Failed to bind {}: {}", sa, e),
+                    }
+                }
+                sockets
+            }
+            Listen::Systemd => systemd_sockets()?,
+            Listen::Inetd => vec![inetd_socket()?],
+        };
+        if let Some(dscp) = dscp {
+            for socket in &server_sockets {
+                crate::qos::set_dscp(socket, dscp)
+                    .map_err(|e| anyhow::anyhow!("Cannot set DSCP: {}", e))?;
+            }
+        }
+        for socket in &server_sockets {
+            socket_buffers.apply(socket, "listening socket")?;
+            if let Some(ref ifname) = bind_device {
+                crate::device::bind_to_device(socket, ifname)?;
+            }
+        }
+        let mut replay_guard = ReplayGuard::new(replay_window);
+        if let Some(max_nonces) = max_nonces {
+            replay_guard = replay_guard.with_max_len(max_nonces);
+        }
+        if let Some(ref path) = nonce_store {
+            replay_guard.load(path).map_err(|e| {
+                anyhow::anyhow!("Failed to load nonce store {}: {}", path.display(), e)
+            })?;
+            info!(
+                "Loaded {} nonces from {}",
+                replay_guard.len(),
+                path.display()
+            );
+        }
+        let mosh_addr = match backend_addr {
+            Some(addr) => {
+                // Fail early if there is no route, port does not matter for UDP connect
+                relay_socket(SocketAddr::new(addr, 9))?;
+                addr
+            }
+            None => loopback.addr(),
+        };
+        let metrics = match metrics_addr {
+            Some(addr) => Some(Metrics::bind(addr)?),
+            None => None,
+        };
+        let shutdown = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, shutdown.clone())?;
+        signal_hook::flag::register(signal_hook::consts::SIGINT, shutdown.clone())?;
         Ok(Server {
-            server_socket: UdpSocket::bind(sa)?,
-            crypto,
-            mosh: None,
-            past_nonces: FxHashSet::default(),
-            recent_client_addr: None,
+            server_sockets,
+            keys: std::iter::once(crypto).chain(extra_keys).collect(),
+            compress,
+            pad_to,
+            sessions: FxHashMap::default(),
+            client_sessions: FxHashMap::default(),
+            replay_guard,
+            nonce_store,
+            next_nonce_store_flush: Instant::now() + NONCE_STORE_INTERVAL,
+            cookies: CookieJar::new()?,
+            rate_limiter: max_rate.map(RateLimiter::new),
+            key_mismatch: KeyMismatchDetector::new(),
+            throttle,
+            mtu_check: mtu.map(MtuCheck::new),
+            socket_buffers,
             update_address_cooldown: Instant::now(),
+            buffer_size,
+            mosh_server_args,
+            spawn_cmd,
+            connect_line,
+            spawn_timeout,
+            mosh_port_range,
+            mosh_addr,
+            forward_to,
+            idle_timeout,
+            handshake_timeout,
+            max_sessions,
+            once,
+            had_session: false,
+            // Listening sockets are not given to the poller yet
+            sessions_changed: true,
+            next_mosh_server_check: Instant::now(),
+            started: Instant::now(),
+            counters: Counters::default(),
+            stats_interval,
+            next_stats: Instant::now() + stats_interval.unwrap_or_default(),
+            metrics,
+            shutdown,
         })
     }
 
     pub fn serve(&mut self) {
-        let mut buf = [0u8; 8192];
-        let mut polls: Vec<PollFd> = Vec::with_capacity(2);
-        polls.push(PollFd::new(
-            self.server_socket.as_raw_fd(),
-            PollFlags::POLLIN,
-        ));
+        // One extra byte to detect datagrams that do not fit
+        let mut buf = vec![0u8; self.buffer_size + 1];
+        let mut batch = RecvBatch::new(self.buffer_size + 1);
+        let mut poller = PlatformPoller::default();
+        if let Some(ref mut metrics) = self.metrics {
+            metrics.start();
+        }
+        if let Err(e) =
+            poller.interrupt_on(&[signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT])
+        {
+            warn!("Cannot watch for signals: {}", e);
+        }
+        // Session ids and mosh socket fds in the same order as sockets given to `poller`
+        let mut polled_sessions: Vec<(u64, RawFd)> = Vec::with_capacity(1);
         loop {
-            polls.truncate(1);
-            if let Some(ref mosh) = self.mosh {
-                polls.push(PollFd::new(mosh.socket.as_raw_fd(), PollFlags::POLLIN));
+            if self.shutdown.load(Ordering::Relaxed) {
+                info!(
+                    "Shutting down, stopping {} mosh-servers",
+                    self.sessions.len()
+                );
+                // Dropping `MoshState`s sends SIGTERM to mosh-servers
+                self.sessions.clear();
+                self.client_sessions.clear();
+                self.flush_nonce_store();
+                return;
             }
-
-            if let Err(e) = poll(&mut polls[..], -1) {
-                eprintln!("poll error: {}", e);
+            if self.once && self.had_session && self.sessions.is_empty() {
+                info!("Session ended, exiting");
+                self.flush_nonce_store();
                 return;
             }
+            let nlisteners = self.server_sockets.len();
+            if self.sessions_changed {
+                self.sessions_changed = false;
+                polled_sessions.clear();
+                let mut sockets: Vec<&dyn Pollable> =
+                    Vec::with_capacity(nlisteners + self.sessions.len());
+                sockets.extend(self.server_sockets.iter().map(|x| x as &dyn Pollable));
+                for (sessid, mosh) in &self.sessions {
+                    sockets.push(&mosh.socket);
+                    polled_sessions.push((*sessid, mosh.socket.as_raw_fd()));
+                }
+                if let Err(e) = poller.set_sockets(&sockets) {
+                    error!("Cannot watch sockets: {}", e);
+                    return;
+                }
+            }
 
-            if matches!(polls[0].revents(), Some(x) if x.contains(PollFlags::POLLIN)) {
-                let (pkt, clientaddr) = match self.server_socket.recv_from(&mut buf) {
-                    Ok((sz, clientaddr)) => (&buf[..sz], clientaddr),
-                    Err(_) => continue,
-                };
+            match poller.poll(self.poll_timeout()) {
+                // Signal arrived, check `shutdown` flag
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("poll error: {}", e);
+                    return;
+                }
+                Ok(_) => (),
+            }
+            self.expire_idle_sessions();
+            self.expire_unused_sessions();
+            self.reap_exited_mosh_servers();
+            if Instant::now() >= self.next_nonce_store_flush {
+                self.flush_nonce_store();
+            }
+            if self.stats_interval.is_some() && Instant::now() >= self.next_stats {
+                self.log_stats();
+            }
 
-                if Some(clientaddr) == self.recent_client_addr {
-                    self.update_address_cooldown = Instant::now() + UPDATE_ADDRESS_COOLDOWN;
+            for listener in 0..nlisteners {
+                if !poller.is_readable(listener) {
+                    continue;
+                }
+                if batch.recv_from(&self.server_sockets[listener]).is_err() {
+                    continue;
+                }
+                for i in 0..batch.len() {
+                    let (pkt, clientaddr) = batch.get(i);
+                    if pkt.len() > self.buffer_size {
+                        warn!(
+                            "Dropping datagram from {}: larger than --buffer-size {}, may be truncated",
+                            clientaddr, self.buffer_size
+                        );
+                        continue;
+                    }
+                    self.handle_datagram(listener, pkt, clientaddr);
+                }
+            }
+            for (i, (sessid, fd)) in polled_sessions.iter().enumerate() {
+                if !poller.is_readable(nlisteners + i) {
+                    continue;
+                }
+                // The session may have been removed or replaced while handling server socket
+                let mosh = match self.sessions.get_mut(sessid) {
+                    Some(x) if x.socket.as_raw_fd() == *fd => x,
+                    _ => continue,
+                };
+                let mut clearmosh = false;
+                let pkt = match mosh.socket.recv(&mut buf) {
+                    Ok(sz) => &buf[..sz],
+                    Err(e) if is_transient(&e) => continue,
+                    Err(e) => {
+                        warn!(
+                            "Cannot receive from mosh-server of session {:016x}: {}",
+                            sessid, e
+                        );
+                        clearmosh = true;
+                        &buf[..]
+                    }
+                };
+                if clearmosh {
+                    self.remove_session(*sessid);
+                } else if pkt.len() > self.buffer_size {
+                    warn!(
+                        "Dropping datagram from mosh-server: larger than --buffer-size {}, may be truncated",
+                        self.buffer_size
+                    );
                 } else {
+                    mosh.last_activity = Instant::now();
+                    let allowed = match mosh.throttle {
+                        Some(ref mut throttle) => throttle.admit(pkt),
+                        None => true,
+                    };
+                    if allowed {
+                        if let Some(ref mtu_check) = self.mtu_check {
+                            mtu_check.check(pkt.len(), mosh.client_addr);
+                        }
+                        let socket = &self.server_sockets[mosh.listener];
+                        trace::sent(socket, mosh.client_addr, pkt.len(), Payload::Mosh);
+                        mosh.traffic.sent(pkt.len());
+                        self.counters.traffic.sent(pkt.len());
+                        let _ = socket.send_to(pkt, mosh.client_addr);
+                    }
                 }
+            }
+            self.send_throttled();
+            if let Some(ref metrics) = self.metrics {
+                metrics.update(
+                    self.sessions.len(),
+                    self.counters,
+                    self.replay_guard.len(),
+                    self.replay_guard.evicted(),
+                );
+            }
+        }
+    }
 
-                let msg: Option<Message> =
-                    match crate::protocol::decrypt(pkt, &self.crypto, &mut self.past_nonces) {
-                        Ok(x) => Some(x),
-                        Err(_e) => {
-                            //eprintln!("{}", _e);
-                            if Some(clientaddr) == self.recent_client_addr {
-                                let mut clearmosh = false;
-                                if let Some(ref mosh) = self.mosh {
-                                    if mosh.socket.send(pkt).is_err() {
-                                        clearmosh = true;
-                                    }
-                                }
-                                if clearmosh {
-                                    self.mosh = None
-                                }
-                                continue;
-                            } else if Instant::now() >= self.update_address_cooldown
-                                && Instant::now()
-                                    < self.update_address_cooldown + MOSH_SERVER_TIMEOUT
-                                && self.mosh.is_some()
-                            {
-                                self.update_address_cooldown =
-                                    Instant::now() + UPDATE_ADDRESS_COOLDOWN;
-                                None
-                            } else {
-                                continue;
+    /// Handles a datagram that came to listening socket number `listener`
+    fn handle_datagram(&mut self, listener: usize, pkt: &[u8], clientaddr: SocketAddr) {
+        self.counters.traffic.received(pkt.len());
+        let client_sessid = self.client_sessions.get(&clientaddr).copied();
+        if let Some(sessid) = client_sessid {
+            if let Some(mosh) = self.sessions.get_mut(&sessid) {
+                mosh.update_address_cooldown = Instant::now() + UPDATE_ADDRESS_COOLDOWN;
+                mosh.last_activity = Instant::now();
+            }
+        }
+
+        if client_sessid.is_none() {
+            if let Some(ref mut rate_limiter) = self.rate_limiter {
+                if !rate_limiter.allow(clientaddr) {
+                    return;
+                }
+            }
+        }
+
+        // Replies go encrypted with the key the client used
+        let (decrypted, key_index) =
+            match crate::protocol::decrypt_any(pkt, &self.keys, &mut self.replay_guard) {
+                Ok((msg, i)) => (Ok(msg), Some(i)),
+                Err(e) => (Err(e), None),
+            };
+        trace::received(
+            &self.server_sockets[listener],
+            clientaddr,
+            pkt.len(),
+            (&decrypted).into(),
+            self.replay_guard.len(),
+        );
+        let msg: Option<Message> = match decrypted {
+            Ok(x) => Some(x),
+            Err(DecryptError::BadMagic) => {
+                if let Some(sessid) = client_sessid {
+                    debug!(
+                        "Forwarding {} bytes from {} to mosh-server of session {:016x}",
+                        pkt.len(),
+                        clientaddr,
+                        sessid
+                    );
+                    let mut clearmosh = false;
+                    if let Some(mosh) = self.sessions.get_mut(&sessid) {
+                        mosh.traffic.received(pkt.len());
+                        match mosh.socket.send(pkt) {
+                            Ok(_) => (),
+                            Err(e) if is_transient(&e) => {
+                                debug!("Dropped datagram to mosh-server: {}", e);
+                            }
+                            Err(e) => {
+                                warn!(
+                                    "Cannot send to mosh-server of session {:016x}: {}",
+                                    sessid, e
+                                );
+                                clearmosh = true;
                             }
                         }
+                    }
+                    if clearmosh {
+                        self.remove_session(sessid);
+                    }
+                    return;
+                } else if self.may_request_address_update() {
+                    debug!("Requesting address update from {}", clientaddr);
+                    self.update_address_cooldown = Instant::now() + UPDATE_ADDRESS_COOLDOWN;
+                    None
+                } else {
+                    debug!("Ignoring non-moshudp datagram from {}", clientaddr);
+                    return;
+                }
+            }
+            Err(e) => {
+                match e {
+                    DecryptError::Replay => self.counters.replay_hits += 1,
+                    DecryptError::AeadFailure | DecryptError::CipherMismatch => {
+                        self.counters.decrypt_failures += 1;
+                        if self.key_mismatch.failed(clientaddr) {
+                            warn!(
+                                "Possible key mismatch from {}: {} datagrams with moshudp magic failed to decrypt, check key and --cipher of the client",
+                                clientaddr, KEY_MISMATCH_BURST
+                            );
+                        }
+                    }
+                    _ => self.counters.decrypt_failures += 1,
+                }
+                debug!("Ignoring datagram from {}: {}", clientaddr, e);
+                return;
+            }
+        };
+
+        let replymsg: Option<Message> = if let Some(msg) = msg {
+            debug!(
+                "Received {:?} from {}, {} nonces remembered",
+                msg,
+                clientaddr,
+                self.replay_guard.len()
+            );
+            // Always set along with `msg`
+            let key_index = key_index.unwrap_or(0);
+            match msg {
+                Message::Ping { seq } => Some(Message::Pong { seq }),
+                Message::Pong { .. } => None,
+                Message::ServerStarted { .. } => None,
+                Message::StartServer { sessid, cookie } => {
+                    let cookie_ok = match cookie {
+                        Some(ref token) => self.cookies.check(clientaddr, token),
+                        None => false,
                     };
-                if self.past_nonces.len() > 1_000_000 {
-                    self.past_nonces.clear();
-                }
-
-                let replymsg: Option<Message> = if let Some(msg) = msg {
-                    match msg {
-                        Message::Ping => Some(Message::Pong),
-                        Message::Pong => None,
-                        Message::ServerStarted { .. } => None,
-                        Message::StartServer { sessid } => {
-                            self.recent_client_addr = Some(clientaddr);
-                            let reply = if let Some(ref mosh) = self.mosh {
-                                if mosh.sessid == sessid {
-                                    Some(Message::ServerStarted {
-                                        key: mosh.key.clone(),
-                                    })
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            };
-                            if reply.is_none() {
-                                match Server::start_mosh_server(sessid) {
-                                    Ok(mosh) => {
-                                        let key = mosh.key.clone();
-                                        self.mosh = Some(mosh);
-                                        Some(Message::ServerStarted { key })
-                                    }
-                                    Err(e) => {
-                                        self.mosh = None;
-                                        Some(Message::Failed {
-                                            msg: format!("{}", e),
-                                        })
-                                    }
+                    // Start a new mosh-server instead of handing out key of a dead one
+                    if let Some(mosh) = self.sessions.get_mut(&sessid) {
+                        if !mosh.is_alive() {
+                            info!(
+                                "mosh-server of session {:016x} is gone, client {} asks again",
+                                sessid, clientaddr
+                            );
+                            self.remove_session(sessid);
+                        }
+                    }
+                    if let Some(mosh) = self.sessions.get(&sessid) {
+                        let key = mosh.key.clone();
+                        self.set_client_addr(sessid, clientaddr, listener, key_index);
+                        Some(Message::ServerStarted { key })
+                    } else if !cookie_ok {
+                        debug!("Sending cookie to {}", clientaddr);
+                        self.cookies
+                            .issue(clientaddr)
+                            .map(|token| Message::Cookie { token })
+                    } else if self.once && self.had_session {
+                        warn!(
+                            "Refusing to start another session for {} in --once mode",
+                            clientaddr
+                        );
+                        Some(Message::failed("server accepts only one session"))
+                    } else if matches!(self.max_sessions, Some(x) if self.sessions.len() >= x) {
+                        warn!(
+                            "Refusing to start session for {}: {} sessions are active",
+                            clientaddr,
+                            self.sessions.len()
+                        );
+                        Some(Message::failed("server at capacity"))
+                    } else {
+                        match self.start_mosh_server(clientaddr, listener) {
+                            Ok(mosh) => {
+                                // Port to look for in ss(8) output. Never log the key itself.
+                                let peer = mosh.socket.peer();
+                                match self.forward_to {
+                                    Some(_) => info!(
+                                        "Forwarding session {:016x} of client {} to {}",
+                                        sessid, clientaddr, peer
+                                    ),
+                                    None => info!(
+                                        "Started mosh-server at {} with {}-byte key for session {:016x} of client {}",
+                                        peer,
+                                        mosh.key.len(),
+                                        sessid,
+                                        clientaddr
+                                    ),
                                 }
-                            } else {
-                                reply
+                                let key = mosh.key.clone();
+                                self.sessions.insert(sessid, mosh);
+                                self.sessions_changed = true;
+                                self.had_session = true;
+                                self.set_client_addr(sessid, clientaddr, listener, key_index);
+                                Some(Message::ServerStarted { key })
                             }
-                        }
-                        Message::Failed { .. } => None,
-                        Message::UpdateAddress => {
-                            self.recent_client_addr = Some(clientaddr);
-                            None
+                            Err(e) => Some(Message::failed(format!("{}", e))),
                         }
                     }
-                } else {
-                    /* Request the client to send back UpdateAddress  */
-                    Some(Message::UpdateAddress)
-                };
-
-                if let Some(replymsg) = replymsg {
-                    if let Ok(pkt2) = crate::protocol::encrypt(&replymsg, &self.crypto) {
-                        let _ = self.server_socket.send_to(&pkt2[..], clientaddr);
+                }
+                Message::Failed { .. } => None,
+                Message::Cookie { .. } => None,
+                Message::Status => Some(Message::StatusReply {
+                    active_sessions: self.sessions.len() as u64,
+                    uptime_secs: self.started.elapsed().as_secs(),
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    counters: self.counters,
+                }),
+                Message::StatusReply { .. } => None,
+                Message::UpdateAddress { sessid } => {
+                    if self.sessions.contains_key(&sessid) {
+                        self.set_client_addr(sessid, clientaddr, listener, key_index);
+                    }
+                    None
+                }
+                Message::Bye { sessid } => {
+                    if self.sessions.contains_key(&sessid) {
+                        info!(
+                            "Client {} ended session {:016x}, stopping its mosh-server",
+                            clientaddr, sessid
+                        );
+                        self.remove_session(sessid);
                     }
+                    None
                 }
-                // end of server socket msg code
             }
-            if polls.len() >= 2
-                && matches!(polls[1].revents(), Some(x) if x.contains(PollFlags::POLLIN))
-            {
-                if let Some(ref mosh) = self.mosh {
-                    let mut clearmosh = false;
-                    let pkt = match mosh.socket.recv(&mut buf) {
-                        Ok(sz) => (&buf[..sz]),
-                        Err(_) => {
-                            clearmosh = true;
-                            &buf[..]
-                        }
-                    };
-                    if clearmosh {
-                        self.mosh = None;
-                        continue;
-                    } else if let Some(ca) = self.recent_client_addr {
-                        let _ = self.server_socket.send_to(pkt, ca);
+        } else {
+            /* Request the client to send back UpdateAddress  */
+            Some(Message::UpdateAddress { sessid: 0 })
+        };
+
+        if let Some(replymsg) = replymsg {
+            // Address update request to unknown client, which may use any of the keys
+            let keys = match key_index {
+                Some(i) => &self.keys[i..=i],
+                None => &self.keys[..],
+            };
+            for crypto in keys {
+                if let Ok(pkt2) =
+                    crate::protocol::encrypt(&replymsg, crypto, self.compress, self.pad_to)
+                {
+                    let socket = &self.server_sockets[listener];
+                    trace::sent(socket, clientaddr, pkt2.len(), Payload::Message(&replymsg));
+                    self.counters.traffic.sent(pkt2.len());
+                    let _ = socket.send_to(&pkt2[..], clientaddr);
+                }
+            }
+        }
+    }
+
+    /// Milliseconds until the earliest session becomes idle or unused or may send a delayed datagram,
+    /// or -1 for infinite poll
+    fn poll_timeout(&self) -> i32 {
+        let now = Instant::now();
+        let idle = self.idle_timeout.and_then(|idle_timeout| {
+            let t = self
+                .sessions
+                .values()
+                .map(|mosh| mosh.last_activity)
+                .min()?;
+            Some((t + idle_timeout).saturating_duration_since(now))
+        });
+        let unused = self.handshake_timeout.and_then(|handshake_timeout| {
+            let t = self
+                .sessions
+                .values()
+                .filter(|mosh| mosh.traffic.packets_in == 0)
+                .map(|mosh| mosh.created)
+                .min()?;
+            Some((t + handshake_timeout).saturating_duration_since(now))
+        });
+        let throttled = self
+            .sessions
+            .values()
+            .filter_map(|mosh| mosh.throttle.as_ref()?.next_ready_in())
+            .min();
+        let flush = self
+            .nonce_store
+            .as_ref()
+            .map(|_| self.next_nonce_store_flush.saturating_duration_since(now));
+        // Notice exited mosh-servers without waiting for traffic, to tell their clients
+        let watched = self.sessions.values().any(|mosh| mosh.pid.is_some())
+            || (self.once && !self.sessions.is_empty());
+        let reap = watched.then(|| self.next_mosh_server_check.saturating_duration_since(now));
+        let stats = self
+            .stats_interval
+            .map(|_| self.next_stats.saturating_duration_since(now));
+        match idle
+            .into_iter()
+            .chain(throttled)
+            .chain(flush)
+            .chain(reap)
+            .chain(stats)
+            .chain(unused)
+            .min()
+        {
+            Some(remaining) => {
+                // round up to avoid waking up just before the deadline
+                (remaining.as_millis() + 1).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        }
+    }
+
+    /// Save remembered nonces to `--nonce-store`, if set
+    fn flush_nonce_store(&mut self) {
+        self.next_nonce_store_flush = Instant::now() + NONCE_STORE_INTERVAL;
+        if let Some(ref path) = self.nonce_store {
+            if let Err(e) = self.replay_guard.save(path) {
+                warn!("Failed to save nonce store {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Log global counters, and those of each session at debug level
+    fn log_stats(&mut self) {
+        self.next_stats = Instant::now() + self.stats_interval.unwrap_or_default();
+        info!(
+            "Stats: {} sessions, {}, {} nonces remembered, {} forgotten early",
+            self.sessions.len(),
+            self.counters,
+            self.replay_guard.len(),
+            self.replay_guard.evicted()
+        );
+        for (sessid, mosh) in &self.sessions {
+            debug!(
+                "Session {:016x} of client {}: {}",
+                sessid, mosh.client_addr, mosh.traffic
+            );
+        }
+    }
+
+    /// Send datagrams delayed by `--rate-limit-queue` that fit into the budget now
+    fn send_throttled(&mut self) {
+        for mosh in self.sessions.values_mut() {
+            if let Some(ref mut throttle) = mosh.throttle {
+                while let Some(pkt) = throttle.pop_ready() {
+                    if let Some(ref mtu_check) = self.mtu_check {
+                        mtu_check.check(pkt.len(), mosh.client_addr);
                     }
-                } else {
-                    unreachable!()
+                    let socket = &self.server_sockets[mosh.listener];
+                    trace::sent(socket, mosh.client_addr, pkt.len(), Payload::Mosh);
+                    mosh.traffic.sent(pkt.len());
+                    self.counters.traffic.sent(pkt.len());
+                    let _ = socket.send_to(&pkt, mosh.client_addr);
+                }
+            }
+        }
+    }
+
+    fn expire_idle_sessions(&mut self) {
+        let idle_timeout = match self.idle_timeout {
+            Some(x) => x,
+            None => return,
+        };
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .sessions
+            .iter()
+            .filter(|(_, mosh)| now.duration_since(mosh.last_activity) >= idle_timeout)
+            .map(|(sessid, _)| *sessid)
+            .collect();
+        for sessid in expired {
+            if let Some(mosh) = self.sessions.get(&sessid) {
+                info!(
+                    "Session {:016x} of client {} expired after idle timeout",
+                    sessid, mosh.client_addr
+                );
+            }
+            self.remove_session(sessid);
+        }
+    }
+
+    /// Stop sessions whose client completed the handshake but never sent mosh traffic
+    fn expire_unused_sessions(&mut self) {
+        let handshake_timeout = match self.handshake_timeout {
+            Some(x) => x,
+            None => return,
+        };
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .sessions
+            .iter()
+            .filter(|(_, mosh)| {
+                mosh.traffic.packets_in == 0
+                    && now.duration_since(mosh.created) >= handshake_timeout
+            })
+            .map(|(sessid, _)| *sessid)
+            .collect();
+        for sessid in expired {
+            if let Some(mosh) = self.sessions.get(&sessid) {
+                info!(
+                    "Session {:016x} of client {} expired: no mosh traffic after handshake",
+                    sessid, mosh.client_addr
+                );
+            }
+            self.remove_session(sessid);
+        }
+    }
+
+    /// mosh-server detaches from us, so instead of waiting for a child process
+    /// just check if the pid is still alive. Clients of sessions with exited
+    /// mosh-server get `Failed` message.
+    fn reap_exited_mosh_servers(&mut self) {
+        let now = Instant::now();
+        if now < self.next_mosh_server_check {
+            return;
+        }
+        self.next_mosh_server_check = now + MOSH_SERVER_CHECK_INTERVAL;
+
+        let exited: Vec<u64> = self
+            .sessions
+            .iter_mut()
+            .filter_map(|(sessid, mosh)| (!mosh.is_alive()).then_some(*sessid))
+            .collect();
+        for sessid in exited {
+            if let Some(mosh) = self.sessions.get(&sessid) {
+                warn!(
+                    "mosh-server of session {:016x} of client {} is gone",
+                    sessid, mosh.client_addr
+                );
+                let msg = Message::failed("mosh-server exited");
+                if let Ok(pkt) = crate::protocol::encrypt(
+                    &msg,
+                    &self.keys[mosh.key_index],
+                    self.compress,
+                    self.pad_to,
+                ) {
+                    let socket = &self.server_sockets[mosh.listener];
+                    trace::sent(socket, mosh.client_addr, pkt.len(), Payload::Message(&msg));
+                    self.counters.traffic.sent(pkt.len());
+                    let _ = socket.send_to(&pkt[..], mosh.client_addr);
+                }
+            }
+            self.remove_session(sessid);
+        }
+    }
+
+    /// Whether an undecryptable datagram from unknown address may be a session's client that
+    /// has changed its address: some session's client has recently been active,
+    /// but not within the cooldown period.
+    fn may_request_address_update(&self) -> bool {
+        let now = Instant::now();
+        now >= self.update_address_cooldown
+            && self.sessions.values().any(|mosh| {
+                now >= mosh.update_address_cooldown
+                    && now < mosh.update_address_cooldown + MOSH_SERVER_TIMEOUT
+            })
+    }
+
+    /// Session `sessid` is now reached at `clientaddr` through `listener`, using key number
+    /// `key_index`. Session previously bound to that address, if any, gets dropped.
+    fn set_client_addr(
+        &mut self,
+        sessid: u64,
+        clientaddr: SocketAddr,
+        listener: usize,
+        key_index: usize,
+    ) {
+        if let Some(oldsessid) = self.client_sessions.insert(clientaddr, sessid) {
+            if oldsessid != sessid {
+                self.sessions.remove(&oldsessid);
+                self.sessions_changed = true;
+            }
+        }
+        if let Some(mosh) = self.sessions.get_mut(&sessid) {
+            if mosh.client_addr != clientaddr {
+                if self.client_sessions.get(&mosh.client_addr) == Some(&sessid) {
+                    self.client_sessions.remove(&mosh.client_addr);
                 }
+                mosh.client_addr = clientaddr;
+            }
+            mosh.listener = listener;
+            mosh.key_index = key_index;
+            mosh.update_address_cooldown = Instant::now() + UPDATE_ADDRESS_COOLDOWN;
+        }
+    }
+
+    fn remove_session(&mut self, sessid: u64) {
+        if let Some(mosh) = self.sessions.remove(&sessid) {
+            self.sessions_changed = true;
+            debug!("Session {:016x} ended: {}", sessid, mosh.traffic);
+            if self.client_sessions.get(&mosh.client_addr) == Some(&sessid) {
+                self.client_sessions.remove(&mosh.client_addr);
             }
         }
     }
 
-    fn start_mosh_server(sessid: u64) -> anyhow::Result<MoshState> {
-        let mosh_server = std::env::var_os("MOSH_SERVER")
-            .unwrap_or_else(|| OsStr::from_bytes(b"mosh-server").to_owned());
-        let mut cmd = std::process::Command::new(mosh_server);
-        cmd.arg("new").arg("-i").arg("127.0.0.1").arg("-p").arg("0");
-        let out = cmd.output()?;
+    fn start_mosh_server(
+        &self,
+        client_addr: SocketAddr,
+        listener: usize,
+    ) -> anyhow::Result<MoshState> {
+        if let Some(ref backend) = self.forward_to {
+            // Nothing to start, and no key for the client
+            let socket = self.mosh_socket(backend)?;
+            return Ok(self.new_session(socket, String::new(), None, client_addr, listener));
+        }
+        let mosh_addr = self.mosh_addr;
+        let port = if self.spawn_cmd.iter().any(|x| x.contains("{port}")) {
+            // The program binds it after this socket is closed
+            UdpSocket::bind(SocketAddr::new(mosh_addr, 0))
+                .map_err(|e| {
+                    anyhow::anyhow!("Cannot find free port on {} for {{port}}: {}", mosh_addr, e)
+                })?
+                .local_addr()?
+                .port()
+        } else {
+            0
+        };
+        let mut cmd = mosh_server_command(
+            &self.spawn_cmd,
+            &self.mosh_server_args,
+            mosh_addr,
+            self.mosh_port_range,
+            &port.to_string(),
+        )?;
+        let child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let child_pid = Pid::from_raw(child.id() as i32);
+        // Wait in a thread to be able to give up on it. Serving other clients stops until then
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(child.wait_with_output());
+        });
+        let out = match rx.recv_timeout(self.spawn_timeout) {
+            Ok(out) => out?,
+            Err(_) => {
+                let _ = kill(child_pid, Signal::SIGKILL);
+                anyhow::bail!(
+                    "mosh-server did not start in {} seconds",
+                    self.spawn_timeout.as_secs()
+                );
+            }
+        };
 
         if !out.status.success() {
-            anyhow::bail!("Unsuccessful exit status from mosh-server: {}", out.status);
+            // e.g. that there is no free port in `--mosh-port-range`
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            let mut reason = stderr.lines().map(str::trim).filter(|l| !l.is_empty());
+            match reason.next_back() {
+                Some(reason) => anyhow::bail!(
+                    "Unsuccessful exit status from mosh-server: {}: {}",
+                    out.status,
+                    reason
+                ),
+                None => anyhow::bail!("Unsuccessful exit status from mosh-server: {}", out.status),
+            }
         }
 
+        let pid = find_detached_pid(&out.stdout).or_else(|| find_detached_pid(&out.stderr));
+
+        let connect_line = &self.connect_line;
         let l = String::from_utf8_lossy(&out.stdout);
         for line in l.lines() {
-            if line.starts_with("MOSH CONNECT") {
+            if line.starts_with(&connect_line.prefix) {
                 let words: Vec<&str> = line.split_ascii_whitespace().collect();
-                if words.len() < 4 {
-                    anyhow::bail!("Malformed MOSH CONNECT line");
-                }
-                let port = words[2];
-                let key = words[3].to_owned();
-                let port: u16 = port.parse()?;
+                let (port, key) = match (
+                    words.get(connect_line.port_field),
+                    words.get(connect_line.key_field),
+                ) {
+                    (Some(port), Some(key)) => (port, key.to_string()),
+                    _ => anyhow::bail!("Malformed {} line", connect_line.prefix),
+                };
+                // A wrapper may serve the session on a Unix datagram socket instead of a port
+                let backend = match port.strip_prefix("unix:") {
+                    Some(path) => Backend::Unix(PathBuf::from(path)),
+                    None => Backend::Udp(SocketAddr::new(
+                        mosh_addr,
+                        port.parse().map_err(|_| {
+                            anyhow::anyhow!(
+                                "Invalid port {:?} in {} line",
+                                port,
+                                connect_line.prefix
+                            )
+                        })?,
+                    )),
+                };
+                // Programs run by `--spawn-cmd` may use keys of their own format
+                anyhow::ensure!(
+                    !self.spawn_cmd.is_empty() || is_mosh_key(&key),
+                    "Invalid key in {} line: expected {} base64 characters, got {} characters",
+                    connect_line.prefix,
+                    MOSH_KEY_LEN,
+                    key.len()
+                );
+
+                let socket = self.mosh_socket(&backend)?;
+                return Ok(self.new_session(socket, key, pid, client_addr, listener));
+            }
+        }
+        anyhow::bail!("Failed to find {} in the output", connect_line.prefix)
+    }
 
-                let socket =
-                    UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)))?;
-                socket.connect(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)))?;
-                return Ok(MoshState {
-                    socket,
-                    key,
-                    sessid,
-                });
+    /// [`relay_socket`] with `--rcvbuf` and `--sndbuf` applied, or [`UnixRelay`]
+    fn mosh_socket(&self, backend: &Backend) -> anyhow::Result<MoshSocket> {
+        match backend {
+            Backend::Udp(addr) => {
+                let socket = relay_socket(*addr)?;
+                self.socket_buffers.apply(&socket, "mosh-server socket")?;
+                Ok(MoshSocket::Udp(socket))
             }
+            Backend::Unix(path) => Ok(MoshSocket::Unix(UnixRelay::connect(path)?)),
+        }
+    }
+
+    fn new_session(
+        &self,
+        socket: MoshSocket,
+        key: String,
+        pid: Option<Pid>,
+        client_addr: SocketAddr,
+        listener: usize,
+    ) -> MoshState {
+        MoshState {
+            socket,
+            key,
+            key_index: 0,
+            client_addr,
+            listener,
+            update_address_cooldown: Instant::now(),
+            pid,
+            last_activity: Instant::now(),
+            throttle: self
+                .throttle
+                .map(|opts| Throttle::new(opts, self.buffer_size)),
+            traffic: Traffic::default(),
+            created: Instant::now(),
+        }
+    }
+}
+
+/// Path of mosh-server that [`Server`] starts: `MOSH_SERVER` or `mosh-server` found in `PATH`
+pub fn find_mosh_server() -> anyhow::Result<PathBuf> {
+    let mosh_server = std::env::var_os("MOSH_SERVER")
+        .unwrap_or_else(|| OsStr::from_bytes(b"mosh-server").to_owned());
+    crate::exe::ensure_exists(&mosh_server, "MOSH_SERVER")
+}
+
+/// `mosh-server new` listening on `mosh_addr`, or `spawn_cmd` with `{ip}` and `{port}` replaced
+fn mosh_server_command(
+    spawn_cmd: &[String],
+    mosh_server_args: &[String],
+    mosh_addr: IpAddr,
+    port_range: Option<PortRange>,
+    port: &str,
+) -> anyhow::Result<Command> {
+    if !spawn_cmd.is_empty() {
+        let mut args = spawn_cmd.iter().map(|x| {
+            x.replace("{ip}", &mosh_addr.to_string())
+                .replace("{port}", port)
+        });
+        let mut cmd = Command::new(args.next().unwrap_or_default());
+        cmd.args(args);
+        return Ok(cmd);
+    }
+    let mut cmd = Command::new(find_mosh_server()?);
+    let port = match port_range {
+        Some(range) => format!("{}:{}", range.low, range.high),
+        None => "0".to_owned(),
+    };
+    cmd.arg("new")
+        .arg("-i")
+        .arg(mosh_addr.to_string())
+        .arg("-p")
+        .arg(port);
+    cmd.args(mosh_server_args);
+    Ok(cmd)
+}
+
+/// `serve --dry-run`: prints what [`Server`] would listen on and run for each session, without
+/// binding sockets or starting anything
+pub fn dry_run(listen: &Listen, opts: &ServerOptions) -> anyhow::Result<()> {
+    match listen {
+        Listen::Bind(addrs) => {
+            for sa in addrs {
+                println!("Would listen on {}", sa);
+            }
+        }
+        Listen::Systemd => println!("Would listen on sockets passed by systemd"),
+        Listen::Inetd => println!("Would listen on the socket passed by inetd as standard input"),
+    }
+    if let Some(ref backend) = opts.forward_to {
+        println!("Would forward every session to {}", backend);
+        return Ok(());
+    }
+    let mosh_addr = opts.backend_addr.unwrap_or_else(|| opts.loopback.addr());
+    // A free port is picked for `{port}` of each session
+    let cmd = mosh_server_command(
+        &opts.spawn_cmd,
+        &opts.mosh_server_args,
+        mosh_addr,
+        opts.mosh_port_range,
+        "{port}",
+    )?;
+    println!(
+        "Would run {} for each session",
+        crate::exe::describe_command(&cmd)
+    );
+    Ok(())
+}
+
+/// Socket for forwarding to mosh-server at `addr`, bound to loopback if `addr` is loopback
+fn relay_socket(addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let local = match addr.ip() {
+        x if x.is_loopback() => x,
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))?;
+    socket
+        .connect(addr)
+        .map_err(|e| anyhow::anyhow!("Cannot reach mosh-server at {}: {}", addr, e))?;
+    Ok(socket)
+}
+
+/// Token bucket per source address
+struct RateLimiter {
+    /// Tokens per second, also the bucket size
+    rate: f64,
+    buckets: FxHashMap<SocketAddr, (f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            buckets: FxHashMap::default(),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    /// Takes a token from the bucket of `addr`, returns `false` if there is none
+    fn allow(&mut self, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        if self.buckets.len() >= RATE_LIMITER_MAX_SOURCES && !self.buckets.contains_key(&addr) {
+            self.forget_full_buckets(now);
+        }
+        let capacity = self.capacity();
+        let rate = self.rate;
+        let (tokens, last) = self.buckets.entry(addr).or_insert((capacity, now));
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(capacity);
+        *last = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    /// Buckets that would have been refilled to capacity are the same as absent ones
+    fn forget_full_buckets(&mut self, now: Instant) {
+        let capacity = self.capacity();
+        let rate = self.rate;
+        self.buckets.retain(|_, (tokens, last)| {
+            *tokens + now.duration_since(*last).as_secs_f64() * rate < capacity
+        });
+    }
+}
+
+/// Counts datagrams per source address that have the right magic and version but cannot be
+/// decrypted. Random noise rarely gets that far, a client with the wrong key does every time.
+struct KeyMismatchDetector {
+    failures: FxHashMap<SocketAddr, u32>,
+    /// When `failures` is cleared next
+    reset_at: Instant,
+}
+
+impl KeyMismatchDetector {
+    fn new() -> KeyMismatchDetector {
+        KeyMismatchDetector {
+            failures: FxHashMap::default(),
+            reset_at: Instant::now() + KEY_MISMATCH_WINDOW,
+        }
+    }
+
+    /// Returns `true` once per window, when failures from `addr` reach [`KEY_MISMATCH_BURST`]
+    fn failed(&mut self, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        if now >= self.reset_at {
+            self.failures.clear();
+            self.reset_at = now + KEY_MISMATCH_WINDOW;
+        }
+        if self.failures.len() >= RATE_LIMITER_MAX_SOURCES && !self.failures.contains_key(&addr) {
+            return false;
+        }
+        let n = self.failures.entry(addr).or_insert(0);
+        *n += 1;
+        *n == KEY_MISMATCH_BURST
+    }
+}
+
+/// mosh session key is 128 bits in unpadded base64
+const MOSH_KEY_LEN: usize = 22;
+
+/// Whether `key` looks like a key for mosh-client's `MOSH_KEY`
+fn is_mosh_key(key: &str) -> bool {
+    key.len() == MOSH_KEY_LEN
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+/// Parses `[mosh-server detached, pid = 1234]` line from mosh-server output
+fn find_detached_pid(output: &[u8]) -> Option<Pid> {
+    let l = String::from_utf8_lossy(output);
+    for line in l.lines() {
+        if let Some(rest) = line.trim().strip_prefix("[mosh-server detached, pid = ") {
+            let pid: i32 = rest.strip_suffix(']')?.parse().ok()?;
+            return Some(Pid::from_raw(pid));
         }
-        anyhow::bail!("Failed to find MOSH CONNECT in the output")
     }
+    None
 }
@@ -0,0 +1,79 @@
+//! `--rcvbuf` and `--sndbuf`: kernel buffer sizes of UDP sockets, for paths with large
+//! bandwidth-delay product where default buffers overflow.
+
+use std::net::UdpSocket;
+
+use log::{info, warn};
+
+/// Requested `SO_RCVBUF` and `SO_SNDBUF` sizes in bytes, `None` to keep system default
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SocketBuffers {
+    pub rcvbuf: Option<usize>,
+    pub sndbuf: Option<usize>,
+}
+
+impl SocketBuffers {
+    /// Sets requested sizes on `socket` and logs what the kernel granted, which may be
+    /// clamped by system limits. `what` names the socket in log messages.
+    pub(crate) fn apply(&self, socket: &UdpSocket, what: &str) -> anyhow::Result<()> {
+        if let Some(size) = self.rcvbuf {
+            let granted = set_rcvbuf(socket, size)
+                .map_err(|e| anyhow::anyhow!("Cannot set receive buffer of {}: {}", what, e))?;
+            report(what, "receive", size, granted);
+        }
+        if let Some(size) = self.sndbuf {
+            let granted = set_sndbuf(socket, size)
+                .map_err(|e| anyhow::anyhow!("Cannot set send buffer of {}: {}", what, e))?;
+            report(what, "send", size, granted);
+        }
+        Ok(())
+    }
+}
+
+fn report(what: &str, direction: &str, requested: usize, granted: usize) {
+    // Linux reports double the requested size to account for bookkeeping overhead
+    if granted < requested {
+        warn!(
+            "Kernel granted {}-byte {} buffer of {} instead of {} bytes, check system limits like net.core.rmem_max and net.core.wmem_max",
+            granted, direction, what, requested
+        );
+    } else {
+        info!("Got {}-byte {} buffer for {}", granted, direction, what);
+    }
+}
+
+#[cfg(unix)]
+fn set_rcvbuf(socket: &UdpSocket, size: usize) -> std::io::Result<usize> {
+    use nix::sys::socket::{getsockopt, setsockopt, sockopt::RcvBuf};
+    use std::os::unix::io::AsRawFd;
+
+    setsockopt(socket.as_raw_fd(), RcvBuf, &size)?;
+    Ok(getsockopt(socket.as_raw_fd(), RcvBuf)?)
+}
+
+#[cfg(unix)]
+fn set_sndbuf(socket: &UdpSocket, size: usize) -> std::io::Result<usize> {
+    use nix::sys::socket::{getsockopt, setsockopt, sockopt::SndBuf};
+    use std::os::unix::io::AsRawFd;
+
+    setsockopt(socket.as_raw_fd(), SndBuf, &size)?;
+    Ok(getsockopt(socket.as_raw_fd(), SndBuf)?)
+}
+
+#[cfg(not(unix))]
+fn set_rcvbuf(_socket: &UdpSocket, _size: usize) -> std::io::Result<usize> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn set_sndbuf(_socket: &UdpSocket, _size: usize) -> std::io::Result<usize> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Socket buffer sizes are not supported on this platform",
+    )
+}
@@ -0,0 +1,120 @@
+//! Minimal SOCKS5 client for UDP ASSOCIATE (RFC 1928), without authentication.
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// UDP association with a SOCKS5 proxy. It lasts as long as the control connection is open.
+pub struct Socks5Udp {
+    _control: TcpStream,
+    /// Where to send encapsulated datagrams
+    pub relay: SocketAddr,
+}
+
+impl Socks5Udp {
+    pub fn associate(proxy: SocketAddr, timeout: Duration) -> anyhow::Result<Socks5Udp> {
+        let mut control = TcpStream::connect_timeout(&proxy, timeout)?;
+        control.set_read_timeout(Some(timeout))?;
+        control.set_write_timeout(Some(timeout))?;
+
+        control.write_all(&[VERSION, 1, METHOD_NO_AUTH])?;
+        let mut reply = [0u8; 2];
+        control.read_exact(&mut reply)?;
+        if reply != [VERSION, METHOD_NO_AUTH] {
+            anyhow::bail!("SOCKS5 proxy requires authentication or is not a SOCKS5 proxy");
+        }
+
+        // Our address as seen by the proxy is unknown behind NAT, so let the proxy accept any
+        let mut request = vec![VERSION, CMD_UDP_ASSOCIATE, 0];
+        write_addr(
+            &mut request,
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        );
+        control.write_all(&request)?;
+
+        let mut header = [0u8; 3];
+        control.read_exact(&mut header)?;
+        if header[0] != VERSION {
+            anyhow::bail!("Invalid reply from SOCKS5 proxy");
+        }
+        if header[1] != 0 {
+            anyhow::bail!(
+                "SOCKS5 proxy refused UDP ASSOCIATE, reply code {}",
+                header[1]
+            );
+        }
+        let mut relay = read_addr(&mut control)?;
+        if relay.ip().is_unspecified() {
+            relay.set_ip(proxy.ip());
+        }
+        control.set_read_timeout(None)?;
+        Ok(Socks5Udp {
+            _control: control,
+            relay,
+        })
+    }
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn read_addr(r: &mut impl Read) -> anyhow::Result<SocketAddr> {
+    let mut atyp = [0u8; 1];
+    r.read_exact(&mut atyp)?;
+    let ip = match atyp[0] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            r.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            r.read_exact(&mut octets)?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        x => anyhow::bail!("Unsupported address type {} from SOCKS5 proxy", x),
+    };
+    let mut port = [0u8; 2];
+    r.read_exact(&mut port)?;
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+/// Prepends SOCKS5 UDP request header for sending `payload` to `dest` through the relay
+pub fn encapsulate(dest: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(3 + 19 + payload.len());
+    // RSV and FRAG
+    buf.extend_from_slice(&[0, 0, 0]);
+    write_addr(&mut buf, dest);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Strips SOCKS5 UDP header from a datagram received from the relay.
+/// Returns original source address and payload, or `None` for fragments and malformed datagrams.
+pub fn decapsulate(pkt: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if pkt.len() < 3 || pkt[..3] != [0, 0, 0] {
+        return None;
+    }
+    let mut rest = &pkt[3..];
+    let addr = read_addr(&mut rest).ok()?;
+    Some((addr, rest))
+}
@@ -0,0 +1,79 @@
+//! Bandwidth limiting of forwarded mosh traffic.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Settings of [`Throttle`] that come from command line
+#[derive(Clone, Copy)]
+pub struct ThrottleOptions {
+    /// Bytes per second
+    pub rate: u64,
+    /// Number of datagrams to hold back when over budget instead of dropping them
+    pub queue: usize,
+}
+
+/// Token bucket in bytes with optional queue of delayed datagrams
+pub struct Throttle {
+    rate: f64,
+    /// Bucket size, enough for at least one largest datagram
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    queue: VecDeque<Vec<u8>>,
+    queue_limit: usize,
+}
+
+impl Throttle {
+    pub fn new(opts: ThrottleOptions, max_datagram: usize) -> Throttle {
+        let burst = (opts.rate as f64).max(max_datagram as f64);
+        Throttle {
+            rate: opts.rate as f64,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+            queue: VecDeque::new(),
+            queue_limit: opts.queue,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Whether `pkt` may be sent right now. If not, it is queued if there is room or dropped.
+    pub fn admit(&mut self, pkt: &[u8]) -> bool {
+        self.refill();
+        if self.queue.is_empty() && self.tokens >= pkt.len() as f64 {
+            self.tokens -= pkt.len() as f64;
+            return true;
+        }
+        if self.queue.len() < self.queue_limit {
+            self.queue.push_back(pkt.to_vec());
+        }
+        false
+    }
+
+    /// Takes the next queued datagram if budget allows sending it now
+    pub fn pop_ready(&mut self) -> Option<Vec<u8>> {
+        self.refill();
+        let len = self.queue.front()?.len() as f64;
+        if self.tokens < len {
+            return None;
+        }
+        self.tokens -= len;
+        self.queue.pop_front()
+    }
+
+    /// Time until the next queued datagram can be sent, `None` if the queue is empty
+    pub fn next_ready_in(&self) -> Option<Duration> {
+        let len = self.queue.front()?.len() as f64;
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        let missing = len - (self.tokens + elapsed * self.rate);
+        Some(Duration::from_secs_f64((missing / self.rate).max(0.0)))
+    }
+}
@@ -0,0 +1,89 @@
+//! `--trace` log of every datagram exchanged with the network peer.
+//!
+//! Messages go to [`TARGET`] at trace level, so they are shown only when `--trace` (or
+//! `RUST_LOG=moshudp::trace=trace`) enables that target. Keys are redacted by `Debug` of
+//! [`Message`].
+
+use std::{
+    fmt,
+    net::{SocketAddr, UdpSocket},
+};
+
+use log::{log_enabled, trace, Level};
+
+use crate::protocol::{DecryptError, Message};
+
+/// Log target of per-datagram messages
+pub const TARGET: &str = "moshudp::trace";
+
+/// What a datagram carried
+pub(crate) enum Payload<'a> {
+    Message(&'a Message),
+    /// Forwarded mosh traffic
+    Mosh,
+    Invalid(&'a DecryptError),
+}
+
+impl<'a> From<&'a Result<Message, DecryptError>> for Payload<'a> {
+    fn from(x: &'a Result<Message, DecryptError>) -> Self {
+        match x {
+            Ok(msg) => Payload::Message(msg),
+            Err(DecryptError::BadMagic) => Payload::Mosh,
+            Err(e) => Payload::Invalid(e),
+        }
+    }
+}
+
+impl fmt::Display for Payload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Payload::Message(msg) => write!(f, "{:?}", msg),
+            Payload::Mosh => f.write_str("mosh passthrough"),
+            Payload::Invalid(e) => write!(f, "invalid ({})", e),
+        }
+    }
+}
+
+fn local_addr(socket: &UdpSocket) -> String {
+    match socket.local_addr() {
+        Ok(x) => x.to_string(),
+        Err(_) => "?".to_owned(),
+    }
+}
+
+/// Datagram of `len` bytes that came to `socket` from `from`. `nonces` is the replay set size.
+pub(crate) fn received(
+    socket: &UdpSocket,
+    from: SocketAddr,
+    len: usize,
+    payload: Payload,
+    nonces: usize,
+) {
+    if !log_enabled!(target: TARGET, Level::Trace) {
+        return;
+    }
+    trace!(
+        target: TARGET,
+        "recv {} <- {} {} bytes: {}, {} nonces remembered",
+        local_addr(socket),
+        from,
+        len,
+        payload,
+        nonces
+    );
+}
+
+/// Datagram of `len` bytes sent from `socket` to `to`
+pub(crate) fn sent(socket: &UdpSocket, to: SocketAddr, len: usize, payload: Payload) {
+    if !log_enabled!(target: TARGET, Level::Trace) {
+        return;
+    }
+    trace!(
+        target: TARGET,
+        "send {} -> {} {} bytes: {}",
+        local_addr(socket),
+        to,
+        len,
+        payload
+    );
+}
@@ -0,0 +1,193 @@
+//! End-to-end test of `serve` and `connect` on loopback with stub mosh-server and mosh-client.
+//!
+//! The test executable doubles as the stubs, selected by `MOSHUDP_STUB` environment variable
+//! which moshudp passes on to the programs it starts.
+
+#[cfg(unix)]
+fn main() {
+    unix::main()
+}
+
+#[cfg(not(unix))]
+fn main() {}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        io::{BufRead, BufReader},
+        net::UdpSocket,
+        process::{Child, Command, ExitStatus, Stdio},
+        time::{Duration, Instant},
+    };
+
+    use nix::{
+        sys::signal::{kill, Signal},
+        unistd::Pid,
+    };
+
+    /// Session key reported by stub mosh-server, expected by stub mosh-client
+    const MOSH_KEY: &str = "c3R1YmtleXN0dWJrZXkxMg";
+    const PAYLOAD: &[u8] = b"hello through moshudp";
+    const TIMEOUT: Duration = Duration::from_secs(30);
+    /// Server silence after which `connect --reconnect` redoes the handshake
+    const RECONNECT_AFTER: Duration = Duration::from_secs(15);
+
+    pub fn main() {
+        match std::env::var("MOSHUDP_STUB").as_deref() {
+            Ok("server") => stub_server(),
+            Ok("echo") => stub_echo(),
+            Ok("client") => stub_client(false),
+            Ok("lingering-client") => stub_client(true),
+            _ => {
+                handshake();
+                reconnect_to_lost_session();
+            }
+        }
+    }
+
+    /// Pretends to be `mosh-server new`: leaves UDP echo running in background and reports
+    /// its port and pid the way mosh-server does
+    fn stub_server() {
+        let mut echo = Command::new(std::env::current_exe().unwrap())
+            .env("MOSHUDP_STUB", "echo")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut port = String::new();
+        BufReader::new(echo.stdout.take().unwrap())
+            .read_line(&mut port)
+            .unwrap();
+        println!("MOSH CONNECT {} {}", port.trim(), MOSH_KEY);
+        println!("[mosh-server detached, pid = {}]", echo.id());
+        // Left running like detached mosh-server, moshudp kills it with SIGTERM
+        drop(echo);
+    }
+
+    /// Sends every datagram back, prints its port first
+    fn stub_echo() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        println!("{}", socket.local_addr().unwrap().port());
+        let mut buf = [0u8; 2048];
+        while let Ok((n, from)) = socket.recv_from(&mut buf) {
+            let _ = socket.send_to(&buf[..n], from);
+        }
+    }
+
+    /// Pretends to be `mosh-client <host> <port>`: succeeds if the payload comes back.
+    /// With `linger` it then stays running silently, like an idle session.
+    fn stub_client(linger: bool) {
+        let args: Vec<String> = std::env::args().collect();
+        let (host, port) = (&args[args.len() - 2], &args[args.len() - 1]);
+        if std::env::var("MOSH_KEY").as_deref() != Ok(MOSH_KEY) {
+            eprintln!("Wrong MOSH_KEY");
+            std::process::exit(1);
+        }
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(format!("{}:{}", host, port)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut buf = [0u8; 2048];
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            socket.send(PAYLOAD).unwrap();
+            if let Ok(n) = socket.recv(&mut buf) {
+                if &buf[..n] == PAYLOAD {
+                    if linger {
+                        std::thread::sleep(TIMEOUT + RECONNECT_AFTER);
+                    }
+                    std::process::exit(0);
+                }
+            }
+        }
+        eprintln!("No echo received");
+        std::process::exit(1);
+    }
+
+    fn moshudp(args: &[&str], stub: &str, mosh_var: &str) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_moshudp"))
+            .args(args)
+            .args(["--key-env", "MOSHUDP_TEST_KEY"])
+            .env("MOSHUDP_TEST_KEY", base64::encode([7u8; 32]))
+            .env("MOSHUDP_STUB", stub)
+            .env(mosh_var, std::env::current_exe().unwrap())
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap()
+    }
+
+    fn free_addr() -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().to_string()
+    }
+
+    /// Exit status of `child`, or `None` if it had to be killed after `timeout`
+    fn wait_for(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                return Some(status);
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// SIGTERM makes `serve` stop its mosh-servers and exit
+    fn stop(server: &mut Child) {
+        kill(Pid::from_raw(server.id() as i32), Signal::SIGTERM).unwrap();
+        server.wait().unwrap();
+    }
+
+    /// `StartServer` to `ServerStarted` completes and mosh traffic is forwarded both ways
+    fn handshake() {
+        let addr = free_addr();
+        let mut server = moshudp(&["serve", &addr], "server", "MOSH_SERVER");
+        let mut client = moshudp(
+            &["connect", &addr, "--connect-timeout", "20"],
+            "client",
+            "MOSH_CLIENT",
+        );
+
+        let status = wait_for(&mut client, TIMEOUT);
+        stop(&mut server);
+
+        match status {
+            Some(status) => assert!(status.success(), "connect failed: {}", status),
+            None => panic!("connect did not finish in {:?}", TIMEOUT),
+        }
+        println!("handshake ... ok");
+    }
+
+    /// `connect --reconnect` gives up with exit code 5 when the server it comes back to no
+    /// longer has the session, e.g. after a restart, instead of asking for it forever
+    fn reconnect_to_lost_session() {
+        let addr = free_addr();
+        let mut server = moshudp(&["serve", &addr], "server", "MOSH_SERVER");
+        let mut client = moshudp(
+            &["connect", &addr, "--connect-timeout", "20", "--reconnect"],
+            "lingering-client",
+            "MOSH_CLIENT",
+        );
+        // Let the session start, then replace the server with one that never saw it
+        std::thread::sleep(Duration::from_secs(3));
+        stop(&mut server);
+        let mut server = moshudp(&["serve", &addr], "server", "MOSH_SERVER");
+
+        let status = wait_for(&mut client, RECONNECT_AFTER + TIMEOUT);
+        stop(&mut server);
+
+        match status {
+            Some(status) => assert_eq!(status.code(), Some(5), "connect exited with {}", status),
+            None => panic!("connect kept trying to resume a lost session"),
+        }
+        println!("reconnect_to_lost_session ... ok");
+    }
+}